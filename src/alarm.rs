@@ -0,0 +1,237 @@
+//! Module for scheduling many timed callbacks ("alarms") on a single hardware timer.
+//!
+//! Call [`init`] once to reserve the timer. Alarms can then be added with [`add_oneshot`]
+//! and [`add_periodic`]. Their callbacks run from the timer's interrupt handler, so they
+//! should be short, and interrupts must be enabled.
+//! The timer counts in steps of 64 bus cycles (about 1.9 microseconds), which is the resolution of the alarms.
+//!
+//! # Examples
+//!
+//! ```
+//! alarm::init(0);
+//! // blink a cursor every half a second, and stop after 10 seconds
+//! let blink = alarm::add_periodic(Duration::from_millis(500), toggle_cursor);
+//! alarm::add_oneshot(Duration::from_secs(10), move || { alarm::cancel(blink); });
+//! ```
+
+use crate::interrupt::{critical_section, irq_acknowledge, irq_is_pending};
+use crate::sync::NdsMutex;
+use crate::time::duration_to_ticks;
+use crate::timers::{Prescaler, Timer};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+const PRESCALER: Prescaler = Prescaler::Div64;
+// Bus cycles per timer count
+const CYCLES_PER_COUNT: u64 = 64;
+
+/// Identifies an alarm, so it can be cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AlarmId(u32);
+
+struct Alarm {
+    id: u32,
+    // in bus cycles, on the scheduler's clock
+    deadline: u64,
+    period: Option<u64>,
+    callback: Box<dyn FnMut()>,
+}
+
+struct Scheduler {
+    timer: Timer,
+    // Sorted by deadline, latest first, so the next alarm is at the end
+    queue: Vec<Alarm>,
+    // Time (in bus cycles) when the timer was last at its reload value
+    period_start: u64,
+    reload: u16,
+    next_id: u32,
+    // The alarm whose callback is running, and whether it was cancelled by its own callback
+    running: Option<u32>,
+    running_cancelled: bool,
+}
+
+static SCHEDULER: NdsMutex<Option<Scheduler>> = NdsMutex::new(None);
+
+impl Scheduler {
+    /// Number of bus cycles in one full timer period.
+    #[inline(always)]
+    fn period_len(&self) -> u64 {
+        (0x10000 - self.reload as u64) * CYCLES_PER_COUNT
+    }
+
+    /// Current time on the scheduler's clock, in bus cycles. Interrupts must be disabled.
+    fn now(&self) -> u64 {
+        let overflow_flag = self.timer.irq_flag();
+        loop {
+            // if the timer has overflowed but the interrupt hasn't run yet, the period is over
+            let pending_before = irq_is_pending(overflow_flag);
+            let count = self.timer.ticks();
+            let pending_after = irq_is_pending(overflow_flag);
+            if pending_before == pending_after {
+                let elapsed = count.wrapping_sub(self.reload) as u64 * CYCLES_PER_COUNT;
+                let finished = if pending_after { self.period_len() } else { 0 };
+                return self.period_start + finished + elapsed;
+            }
+        }
+    }
+
+    /// Starts the timer so it overflows at the next deadline (or as late as it can, if that's too far away).
+    /// Interrupts must be disabled.
+    fn program(&mut self) {
+        let now = self.now();
+        let counts = match self.queue.last() {
+            Some(next) => next.deadline.saturating_sub(now).div_ceil(CYCLES_PER_COUNT).clamp(1, 0x10000),
+            None => 0x10000,
+        };
+        self.period_start = now;
+        // `now` already includes any overflow that hasn't been handled yet, so the interrupt handler
+        // mustn't add it to `period_start` again
+        irq_acknowledge(self.timer.irq_flag());
+        self.reload = (0x10000 - counts) as u16;
+        self.timer.start(PRESCALER, self.reload);
+    }
+
+    fn insert(&mut self, alarm: Alarm) {
+        // alarms with the same deadline run in the order they were added
+        let pos = self.queue.partition_point(|a| a.deadline > alarm.deadline);
+        self.queue.insert(pos, alarm);
+    }
+}
+
+/// Sets up the scheduler, using hardware timer `timer_index` (0-3).
+///
+/// The timer is reserved forever, and can't be taken with [`Timer::take`]. Calling this again does nothing.
+///
+/// # Panics
+/// Panics if the timer already has a [`Timer`] handle.
+pub fn init(timer_index: u32) {
+    critical_section!({
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.is_none() {
+            let mut timer = Timer::take(timer_index).expect("timer used by alarm::init is already taken");
+            timer.set_overflow_handler(alarm_irq_handler);
+            let mut s = Scheduler {
+                timer,
+                queue: Vec::new(),
+                period_start: 0,
+                reload: 0,
+                next_id: 0,
+                running: None,
+                running_cancelled: false,
+            };
+            s.program();
+            *scheduler = Some(s);
+        }
+    });
+}
+
+fn add(delay: Duration, period: Option<Duration>, callback: Box<dyn FnMut()>) -> AlarmId {
+    let id;
+    critical_section!({
+        let mut scheduler = SCHEDULER.lock();
+        let s = scheduler.as_mut().expect("alarm::init must be called before adding alarms");
+        id = s.next_id;
+        s.next_id = s.next_id.wrapping_add(1);
+        // timer ticks are bus cycles
+        let deadline = s.now().saturating_add(duration_to_ticks(delay));
+        s.insert(Alarm { id, deadline, period: period.map(duration_to_ticks), callback });
+        // no need to reprogram the timer from inside the callbacks, the interrupt handler does it after
+        if s.running.is_none() && s.queue.last().is_some_and(|a| a.id == id) {
+            s.program();
+        }
+    });
+    AlarmId(id)
+}
+
+/// Adds an alarm that runs `callback` once, after `delay`.
+///
+/// The callback never runs early, even if this is called from another interrupt handler
+/// or with interrupts disabled while the alarm timer's interrupt is pending.
+pub fn add_oneshot<F: FnMut() + 'static>(delay: Duration, callback: F) -> AlarmId {
+    add(delay, None, Box::new(callback))
+}
+
+/// Adds an alarm that runs `callback` every `period`, starting after one period.
+///
+/// Each run is scheduled from the previous deadline (not from when the callback actually ran),
+/// so a periodic alarm doesn't drift.
+pub fn add_periodic<F: FnMut() + 'static>(period: Duration, callback: F) -> AlarmId {
+    debug_assert!(!period.is_zero(), "period of a periodic alarm can't be zero");
+    add(period, Some(period), Box::new(callback))
+}
+
+/// Removes an alarm, so its callback won't run again.
+///
+/// Returns `false` if the alarm wasn't found (it was a one-shot alarm that already ran, or was already cancelled).
+/// A periodic alarm can cancel itself from its own callback.
+pub fn cancel(id: AlarmId) -> bool {
+    let mut found = false;
+    let mut removed = None;
+    critical_section!({
+        let mut scheduler = SCHEDULER.lock();
+        if let Some(s) = scheduler.as_mut() {
+            if let Some(pos) = s.queue.iter().position(|a| a.id == id.0) {
+                removed = Some(s.queue.remove(pos));
+                found = true;
+            } else if s.running == Some(id.0) && !s.running_cancelled {
+                s.running_cancelled = true;
+                found = true;
+            }
+        }
+    });
+    // drop the callback outside of the critical section
+    drop(removed);
+    found
+}
+
+/// Gets the number of alarms that are waiting to run.
+#[must_use]
+pub fn pending_count() -> usize {
+    let count;
+    critical_section!({
+        count = SCHEDULER.lock().as_ref().map_or(0, |s| s.queue.len());
+    });
+    count
+}
+
+/// Runs the callbacks of all alarms that are due, then sets the timer for the next one.
+fn alarm_irq_handler() {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(s) = scheduler.as_mut() else { return; };
+        // IF has already been acknowledged, so account for the overflow here
+        s.period_start += s.period_len();
+    }
+    loop {
+        // take the alarm out of the queue while its callback runs, so the callback can add or cancel alarms
+        let mut alarm = {
+            let mut scheduler = SCHEDULER.lock();
+            let Some(s) = scheduler.as_mut() else { return; };
+            let now = s.now();
+            match s.queue.last() {
+                Some(next) if next.deadline <= now => {}
+                _ => {
+                    s.program();
+                    return;
+                }
+            }
+            let Some(alarm) = s.queue.pop() else { return; };
+            s.running = Some(alarm.id);
+            s.running_cancelled = false;
+            alarm
+        };
+
+        (alarm.callback)();
+
+        let mut scheduler = SCHEDULER.lock();
+        let Some(s) = scheduler.as_mut() else { return; };
+        s.running = None;
+        if let Some(period) = alarm.period {
+            if !s.running_cancelled {
+                alarm.deadline = alarm.deadline.saturating_add(period);
+                s.insert(alarm);
+            }
+        }
+    }
+}
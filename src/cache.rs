@@ -0,0 +1,69 @@
+//! Module for managing the ARM9 data cache.
+//!
+//! Only main RAM is cached, so these only need to be used for memory in main RAM that is
+//! also accessed by something other than the ARM9 CPU (DMA, the ARM7, the video hardware...).
+//! See <https://www.problemkaputt.de/gbatek.htm#armcp15cachecontrol>
+
+use core::arch::asm;
+
+/// The size of one data cache line, in bytes.
+pub const CACHE_LINE_SIZE: usize = 32;
+
+/// Gets the range of cache line addresses that cover `len` bytes starting at `ptr`.
+#[inline(always)]
+fn line_range(ptr: *const u8, len: usize) -> (usize, usize) {
+    let start = ptr as usize & !(CACHE_LINE_SIZE - 1);
+    let end = ptr as usize + len;
+    (start, end)
+}
+
+/// Writes any dirty cache lines in a memory range back to memory.
+///
+/// Use this before something else (like DMA or the ARM7) reads memory that the ARM9 wrote to.
+#[instruction_set(arm::a32)]
+#[inline(never)]
+pub fn clean_dcache_range(ptr: *const u8, len: usize) {
+    let (mut addr, end) = line_range(ptr, len);
+    while addr < end {
+        unsafe { asm!("mcr p15, 0, {addr}, c7, c10, 1", addr = in(reg) addr, options(nostack, preserves_flags)); }
+        addr += CACHE_LINE_SIZE;
+    }
+    drain_write_buffer();
+}
+
+/// Throws away the cache lines in a memory range, without writing them back to memory.
+///
+/// Use this after something else (like DMA or the ARM7) wrote to memory that the ARM9 will read.
+/// Be careful: any data the ARM9 wrote to the same cache lines that wasn't written back is lost,
+/// including data just outside the range if it isn't aligned to [`CACHE_LINE_SIZE`].
+/// Use [`flush_dcache_range`] if that might be a problem.
+#[instruction_set(arm::a32)]
+#[inline(never)]
+pub fn invalidate_dcache_range(ptr: *const u8, len: usize) {
+    let (mut addr, end) = line_range(ptr, len);
+    while addr < end {
+        unsafe { asm!("mcr p15, 0, {addr}, c7, c6, 1", addr = in(reg) addr, options(nostack, preserves_flags)); }
+        addr += CACHE_LINE_SIZE;
+    }
+}
+
+/// Writes back, then throws away the cache lines in a memory range.
+///
+/// Unlike [`invalidate_dcache_range`], this never loses data.
+#[instruction_set(arm::a32)]
+#[inline(never)]
+pub fn flush_dcache_range(ptr: *const u8, len: usize) {
+    let (mut addr, end) = line_range(ptr, len);
+    while addr < end {
+        unsafe { asm!("mcr p15, 0, {addr}, c7, c14, 1", addr = in(reg) addr, options(nostack, preserves_flags)); }
+        addr += CACHE_LINE_SIZE;
+    }
+    drain_write_buffer();
+}
+
+/// Waits until all buffered writes have reached memory.
+#[instruction_set(arm::a32)]
+#[inline(never)]
+pub fn drain_write_buffer() {
+    unsafe { asm!("mcr p15, 0, {zero}, c7, c10, 4", zero = in(reg) 0, options(nostack, preserves_flags)); }
+}
@@ -0,0 +1,365 @@
+use super::obj::AffineParameter;
+use super::palette::Color;
+use super::{set_main_bg_control, set_sub_bg_control, BackgroundControl, GfxEngine, PaletteType, ScreenSize};
+use crate::mmio;
+use bitfield_struct::bitfield;
+use core::ptr::{read_volatile, write_volatile};
+use fixed::types::I24F8;
+
+// https://problemkaputt.de/gbatek.htm#lcdiobgscrolling
+// https://problemkaputt.de/gbatek.htm#lcdiobgrotationscaling
+// https://problemkaputt.de/gbatek.htm#dsvideobgmodescontrol
+// https://problemkaputt.de/gbatek.htm#lcdvrambgscreendataformatbgmap
+
+// Bitmap backgrounds use tilemap_base in steps of 16K
+const BITMAP_BASE_SIZE: usize = 16 * 1024;
+// Size of the background memory area of each engine
+const BG_RAM_SIZE_MAIN: usize = 512 * 1024;
+const BG_RAM_SIZE_SUB: usize = 128 * 1024;
+
+/// An entry in the tilemap of a text background, or an extended background with 256 colour tiles.
+#[bitfield(u16)]
+pub struct MapEntry {
+    #[bits(10)]
+    pub tile: u16,
+    pub hflip: bool,
+    pub vflip: bool,
+    /// Palette bank for 16 colour tiles, or the 256 colour palette in the extended palette slot
+    /// for 256 colour tiles (if extended palettes are enabled).
+    #[bits(4)]
+    pub palette: u8,
+}
+
+/// Colour format of a bitmap background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapFormat {
+    /// 8 bits per pixel, using the 256 colour BG palette (not the extended palettes)
+    Paletted,
+    /// 16 bits per pixel. Pixels without bit 15 set are transparent (see [`Color::opaque`]).
+    Direct,
+}
+
+/// The transform for an affine or extended background (BG2 or BG3).
+///
+/// Each screen pixel (sx, sy) shows the background pixel at
+/// `(x + pa * sx + pb * sy, y + pc * sx + pd * sy)`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AffineBackground {
+    pub matrix: AffineParameter,
+    /// Reference point X: the background pixel shown at the top left of the screen.
+    pub x: I24F8,
+    /// Reference point Y: the background pixel shown at the top left of the screen.
+    pub y: I24F8,
+}
+
+impl AffineBackground {
+    /// No transform, the background is drawn normally.
+    pub const IDENTITY: Self = Self { matrix: AffineParameter::IDENTITY, x: I24F8::ZERO, y: I24F8::ZERO };
+
+    /// Uses `matrix`, placed so that the background pixel at (`pivot_x`, `pivot_y`) is drawn at
+    /// screen pixel (`screen_x`, `screen_y`).
+    ///
+    /// Rotation and scaling happen around the pivot, so it stays in place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // rotate a 256x256 background around its centre, which is shown at the centre of the screen
+    /// let matrix = AffineParameter::rotate(angle);
+    /// let bg = AffineBackground::around_pivot(matrix, I24F8::from_num(128), I24F8::from_num(128), 128, 96);
+    /// bg::set_affine(GfxEngine::MAIN, 2, &bg);
+    /// ```
+    #[must_use]
+    pub fn around_pivot(matrix: AffineParameter, pivot_x: I24F8, pivot_y: I24F8, screen_x: i32, screen_y: i32) -> Self {
+        // I8F8 * integer = I24F8
+        let dx = matrix.pa.to_bits() as i32 * screen_x + matrix.pb.to_bits() as i32 * screen_y;
+        let dy = matrix.pc.to_bits() as i32 * screen_x + matrix.pd.to_bits() as i32 * screen_y;
+        Self {
+            matrix,
+            x: pivot_x - I24F8::from_bits(dx),
+            y: pivot_y - I24F8::from_bits(dy),
+        }
+    }
+}
+
+/// Sets the scroll offset of a text background (BG0-BG3, if it isn't affine).
+///
+/// The background pixel at (`x`, `y`) is shown at the top left of the screen. Only the bottom 9 bits are used.
+/// Affine and extended backgrounds ignore this, use [`set_affine`] for them.
+#[inline]
+pub fn set_scroll(engine: GfxEngine, bg: usize, x: u16, y: u16) {
+    debug_assert!(bg <= 3, "background must be from 0 to 3 (was: {bg})");
+    let addr = mmio::BG0XOFS_MAIN.as_usize() + engine as usize + (bg & 3) * 4;
+    // X and Y offsets are next to each other, so write them both at once
+    unsafe { write_volatile(addr as *mut u32, x as u32 | (y as u32) << 16); }
+}
+
+/// Sets the transform of an affine or extended background (BG2 or BG3).
+#[inline]
+pub fn set_affine(engine: GfxEngine, bg: usize, affine: &AffineBackground) {
+    debug_assert!(bg == 2 || bg == 3, "affine background must be 2 or 3 (was: {bg})");
+    let addr = if bg == 3 { mmio::BG3PA_MAIN.as_usize() } else { mmio::BG2PA_MAIN.as_usize() } + engine as usize;
+    let m = &affine.matrix;
+    unsafe {
+        // PA-PD are 16 bit registers next to each other, so write them as pairs
+        write_volatile(addr as *mut u32, m.pa.to_bits() as u16 as u32 | (m.pb.to_bits() as u16 as u32) << 16);
+        write_volatile((addr + 4) as *mut u32, m.pc.to_bits() as u16 as u32 | (m.pd.to_bits() as u16 as u32) << 16);
+        write_volatile((addr + 8) as *mut i32, affine.x.to_bits());
+        write_volatile((addr + 12) as *mut i32, affine.y.to_bits());
+    }
+}
+
+/// Sets only the reference point of an affine or extended background (BG2 or BG3), leaving the matrix alone.
+///
+/// Useful for scrolling an affine background.
+#[inline]
+pub fn set_affine_position(engine: GfxEngine, bg: usize, x: I24F8, y: I24F8) {
+    debug_assert!(bg == 2 || bg == 3, "affine background must be 2 or 3 (was: {bg})");
+    let addr = if bg == 3 { mmio::BG3X_MAIN.as_usize() } else { mmio::BG2X_MAIN.as_usize() } + engine as usize;
+    unsafe {
+        write_volatile(addr as *mut i32, x.to_bits());
+        write_volatile((addr + 4) as *mut i32, y.to_bits());
+    }
+}
+
+/// Gets the extended palette slot used by a background.
+///
+/// BG0 and BG1 use slots 0 and 1, or slots 2 and 3 if `bit13` is set in their [`BackgroundControl`].
+/// BG2 and BG3 always use slots 2 and 3.
+#[must_use]
+#[inline]
+pub const fn ext_palette_slot(bg: usize, bit13: u8) -> usize {
+    match bg {
+        0 | 1 => bg + (bit13 as usize & 1) * 2,
+        _ => bg & 3,
+    }
+}
+
+impl BackgroundControl {
+    /// Control for a text background with 256 colour tiles.
+    ///
+    /// If extended palettes are enabled, each tile uses the 256 colour palette set by [`MapEntry::palette`],
+    /// from the slot picked by [`ext_palette_slot`]. For BG0 and BG1, `alt_ext_palette_slot` picks slots 2 and 3.
+    #[must_use]
+    pub fn text_256(tiledata_base: u8, tilemap_base: u8, size: ScreenSize, alt_ext_palette_slot: bool) -> Self {
+        Self::new()
+            .with_tiledata_base(tiledata_base)
+            .with_tilemap_base(tilemap_base)
+            .with_palette_setting(PaletteType::Colors256)
+            .with_bit13(alt_ext_palette_slot as u8)
+            .with_screen_size(size)
+    }
+
+    /// Control for an extended background (BG2 or BG3) with 256 colour tiles.
+    ///
+    /// Unlike affine backgrounds, the tilemap uses 16 bit [`MapEntry`]s, so tiles can be flipped and use extended palettes.
+    /// If `wrap` is set, the background repeats outside of its area, instead of being transparent.
+    #[must_use]
+    pub fn ext_tiled(tiledata_base: u8, tilemap_base: u8, size: ScreenSize, wrap: bool) -> Self {
+        Self::new()
+            .with_tiledata_base(tiledata_base)
+            .with_tilemap_base(tilemap_base)
+            .with_palette_setting(PaletteType::Colors16)
+            .with_bit13(wrap as u8)
+            .with_screen_size(size)
+    }
+
+    /// Control for an extended background (BG2 or BG3) that shows a bitmap.
+    ///
+    /// The bitmap starts `base` * 16K bytes into the engine's background memory (`base` can be from 0 to 31).
+    /// If `wrap` is set, the background repeats outside of its area, instead of being transparent.
+    #[must_use]
+    pub fn bitmap(format: BitmapFormat, size: ScreenSize, base: u8, wrap: bool) -> Self {
+        // bit 7 set means bitmap, then bit 2 picks direct colour
+        Self::new()
+            .with_tiledata_base((format == BitmapFormat::Direct) as u8)
+            .with_tilemap_base(base)
+            .with_palette_setting(PaletteType::Colors256)
+            .with_bit13(wrap as u8)
+            .with_screen_size(size)
+    }
+}
+
+/// A bitmap background, on BG2 or BG3 of an engine in mode 3, 4 or 5 (depending on the background).
+///
+/// # Examples
+///
+/// ```
+/// // show a 256x256 direct colour image on the main engine (bank A must be mapped to MAIN_BG_0)
+/// display::set_main_display_control(display::DisplayControlMain::new()
+///     .with_bg_mode(display::BgModeMain::Mode5)
+///     .with_display_bg3(true)
+///     .with_display_mode(display::DisplayModeMain::Graphics));
+/// let bitmap = BitmapBg::new(GfxEngine::MAIN, 3, BitmapFormat::Direct, ScreenSize::Size1, 0);
+/// bitmap.init(0);
+/// bitmap.fill_color(Color::BLUE.opaque());
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct BitmapBg {
+    engine: GfxEngine,
+    bg: usize,
+    format: BitmapFormat,
+    size: ScreenSize,
+    base: u8,
+}
+
+impl BitmapBg {
+    /// Describes a bitmap on background `bg` (2 or 3), starting `base` * 16K bytes into the engine's background memory.
+    ///
+    /// This doesn't change any registers, call [`init`](Self::init) to set up the background.
+    ///
+    /// # Panics
+    /// Panics if `bg` isn't 2 or 3, `base` is over 31, or the bitmap doesn't fit in the engine's
+    /// background memory (512K on the main engine, 128K on the sub engine).
+    #[must_use]
+    pub fn new(engine: GfxEngine, bg: usize, format: BitmapFormat, size: ScreenSize, base: u8) -> Self {
+        assert!(bg == 2 || bg == 3, "bitmap background must be 2 or 3 (was: {bg})");
+        assert!(base <= 31, "bitmap base must be from 0 to 31 (was: {base})");
+        let bitmap = Self { engine, bg, format, size, base };
+        let bg_ram_size = match engine {
+            GfxEngine::MAIN => BG_RAM_SIZE_MAIN,
+            GfxEngine::SUB => BG_RAM_SIZE_SUB,
+        };
+        assert!(base as usize * BITMAP_BASE_SIZE + bitmap.bytes() <= bg_ram_size,
+            "bitmap at base {base} doesn't fit in the engine's background memory");
+        bitmap
+    }
+
+    /// Sets the background's control register, with priority `priority`, and resets its transform.
+    pub fn init(&self, priority: u8) {
+        let control = BackgroundControl::bitmap(self.format, self.size, self.base, false).with_priority(priority);
+        match self.engine {
+            GfxEngine::MAIN => set_main_bg_control(self.bg, control),
+            GfxEngine::SUB => set_sub_bg_control(self.bg, control),
+        }
+        set_affine(self.engine, self.bg, &AffineBackground::IDENTITY);
+    }
+
+    /// The width of the bitmap, in pixels.
+    #[must_use]
+    #[inline]
+    pub const fn width(&self) -> usize {
+        match self.size {
+            ScreenSize::Size0 => 128,
+            ScreenSize::Size1 => 256,
+            ScreenSize::Size2 | ScreenSize::Size3 => 512,
+        }
+    }
+
+    /// The height of the bitmap, in pixels.
+    #[must_use]
+    #[inline]
+    pub const fn height(&self) -> usize {
+        match self.size {
+            ScreenSize::Size0 => 128,
+            ScreenSize::Size1 | ScreenSize::Size2 => 256,
+            ScreenSize::Size3 => 512,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn format(&self) -> BitmapFormat {
+        self.format
+    }
+
+    /// The size of the bitmap in bytes.
+    #[must_use]
+    #[inline]
+    pub const fn bytes(&self) -> usize {
+        let pixels = self.width() * self.height();
+        match self.format {
+            BitmapFormat::Paletted => pixels,
+            BitmapFormat::Direct => pixels * 2,
+        }
+    }
+
+    /// Address of the first pixel.
+    #[must_use]
+    #[inline]
+    pub const fn addr(&self) -> usize {
+        let bg_base = match self.engine {
+            GfxEngine::MAIN => mmio::BG_RAM_BASE_MAIN,
+            GfxEngine::SUB => mmio::BG_RAM_BASE_SUB,
+        };
+        bg_base + self.base as usize * BITMAP_BASE_SIZE
+    }
+
+    #[inline(always)]
+    fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        assert!(x < self.width() && y < self.height(), "pixel ({x}, {y}) is outside the bitmap");
+        y * self.width() + x
+    }
+
+    /// Sets a pixel of a direct colour bitmap.
+    #[inline]
+    pub fn set_color(&self, x: usize, y: usize, color: Color) {
+        assert!(self.format == BitmapFormat::Direct, "set_color is only for direct colour bitmaps");
+        let addr = self.addr() + self.pixel_offset(x, y) * 2;
+        unsafe { write_volatile(addr as *mut u16, color.to_bits()); }
+    }
+
+    /// Gets a pixel of a direct colour bitmap.
+    #[must_use]
+    #[inline]
+    pub fn color(&self, x: usize, y: usize) -> Color {
+        assert!(self.format == BitmapFormat::Direct, "color is only for direct colour bitmaps");
+        let addr = self.addr() + self.pixel_offset(x, y) * 2;
+        unsafe { Color::from_bits(read_volatile(addr as *const u16)) }
+    }
+
+    /// Sets a pixel of a paletted bitmap to palette index `index`.
+    #[inline]
+    pub fn set_index(&self, x: usize, y: usize, index: u8) {
+        assert!(self.format == BitmapFormat::Paletted, "set_index is only for paletted bitmaps");
+        let offset = self.pixel_offset(x, y);
+        // VRAM can't be written 8 bits at a time, so change half of the 16 bit pair
+        let addr = (self.addr() + (offset & !1)) as *mut u16;
+        let shift = (offset & 1) * 8;
+        unsafe {
+            let pair = read_volatile(addr) & !(0xFF << shift);
+            write_volatile(addr, pair | (index as u16) << shift);
+        }
+    }
+
+    /// Gets the palette index of a pixel of a paletted bitmap.
+    #[must_use]
+    #[inline]
+    pub fn index(&self, x: usize, y: usize) -> u8 {
+        assert!(self.format == BitmapFormat::Paletted, "index is only for paletted bitmaps");
+        let addr = self.addr() + self.pixel_offset(x, y);
+        unsafe { read_volatile(addr as *const u8) }
+    }
+
+    /// Sets every pixel of a direct colour bitmap to `color`.
+    pub fn fill_color(&self, color: Color) {
+        assert!(self.format == BitmapFormat::Direct, "fill_color is only for direct colour bitmaps");
+        let value = color.to_bits() as u32 * 0x0001_0001;
+        self.fill_words(value);
+    }
+
+    /// Sets every pixel of a paletted bitmap to palette index `index`.
+    pub fn fill_index(&self, index: u8) {
+        assert!(self.format == BitmapFormat::Paletted, "fill_index is only for paletted bitmaps");
+        self.fill_words(index as u32 * 0x0101_0101);
+    }
+
+    fn fill_words(&self, value: u32) {
+        let ptr = self.addr() as *mut u32;
+        for i in 0..self.bytes() / 4 {
+            unsafe { write_volatile(ptr.add(i), value); }
+        }
+    }
+
+    /// Copies raw pixel data into the bitmap, starting at the first pixel.
+    ///
+    /// For paletted bitmaps, each `u16` holds two pixels (the left one in the low byte).
+    pub fn write_raw(&self, data: &[u16]) {
+        assert!(data.len() * 2 <= self.bytes(), "data doesn't fit in the bitmap");
+        let ptr = self.addr() as *mut u16;
+        for (i, &value) in data.iter().enumerate() {
+            unsafe { write_volatile(ptr.add(i), value); }
+        }
+    }
+}
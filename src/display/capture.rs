@@ -0,0 +1,174 @@
+//! Module for capturing the display into a VRAM bank.
+//!
+//! The main engine can write what it outputs (optionally blended with another image) into one of banks A-D.
+//! This can be used for motion blur, taking screenshots, or showing 3D on both screens.
+//! The destination bank has to be mapped to LCDC.
+
+use super::{bitfield_enum, VramDisplayBlock};
+use crate::mmio;
+use crate::sync::NdsCell;
+use bitfield_struct::bitfield;
+use core::ptr::{read_volatile, write_volatile};
+
+// https://problemkaputt.de/gbatek.htm#dsvideocaptureandmainmemorydisplaymode
+
+/// Source A of the capture: the output of the main engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSourceA {
+    /// Backgrounds, sprites and 3D, as shown on screen
+    Graphics = 0,
+    /// Only the 3D rendering
+    Render3d = 1,
+}
+bitfield_enum!(CaptureSourceA: u32 { 0 => Graphics, 1 => Render3d } else Graphics);
+
+/// Source B of the capture: an image from memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSourceB {
+    /// The bank set by `vram_display_block` in the main display control, starting at `read_offset`
+    Vram = 0,
+    /// The main memory display FIFO
+    MainMemory = 1,
+}
+bitfield_enum!(CaptureSourceB: u32 { 0 => Vram, 1 => MainMemory } else Vram);
+
+/// What is written to the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    SourceA = 0,
+    SourceB = 1,
+    /// `(a * eva + b * evb) / 16`
+    Blend = 2,
+}
+bitfield_enum!(CaptureMode: u32 { 0 => SourceA, 1 => SourceB, 2 => Blend, 3 => Blend } else SourceA);
+
+/// Size of the captured area, starting at the top left of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSize {
+    W128H128 = 0,
+    W256H64 = 1,
+    W256H128 = 2,
+    W256H192 = 3,
+}
+bitfield_enum!(CaptureSize: u32 { 0 => W128H128, 1 => W256H64, 2 => W256H128, 3 => W256H192 } else W128H128);
+
+/// Offset into a VRAM bank, for the capture destination and the VRAM source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureOffset {
+    Offset0K = 0,
+    Offset32K = 1,
+    Offset64K = 2,
+    Offset96K = 3,
+}
+bitfield_enum!(CaptureOffset: u32 { 0 => Offset0K, 1 => Offset32K, 2 => Offset64K, 3 => Offset96K } else Offset0K);
+
+#[bitfield(u32)]
+pub struct CaptureControl {
+    /// Weight of source A when blending (0 to 16)
+    #[bits(5)]
+    pub eva: u8,
+    #[bits(3)]
+    _p: u8,
+    /// Weight of source B when blending (0 to 16)
+    #[bits(5)]
+    pub evb: u8,
+    #[bits(3)]
+    _p: u8,
+    #[bits(2)]
+    pub dest_block: VramDisplayBlock,
+    #[bits(2)]
+    pub dest_offset: CaptureOffset,
+    #[bits(2)]
+    pub size: CaptureSize,
+    #[bits(2)]
+    _p: u8,
+    #[bits(1)]
+    pub source_a: CaptureSourceA,
+    #[bits(1)]
+    pub source_b: CaptureSourceB,
+    /// Where source B starts in its bank. Ignored if the main engine is in `DisplayModeMain::Vram`.
+    #[bits(2)]
+    pub read_offset: CaptureOffset,
+    _p: bool,
+    #[bits(2)]
+    pub mode: CaptureMode,
+    /// Set while a capture is waiting to start or running, and cleared once it's finished
+    pub enabled: bool,
+}
+
+// Control used by the continuous capture, rewritten every VBlank. 0 when it's stopped.
+static CONTINUOUS_CAPTURE: NdsCell<u32> = NdsCell::new(0);
+
+#[inline(always)]
+pub fn set_capture_control(c: CaptureControl) {
+    unsafe { write_volatile(mmio::DISPCAPCNT as *mut u32, u32::from(c)); }
+}
+
+#[must_use]
+#[inline(always)]
+pub fn get_capture_control() -> CaptureControl {
+    unsafe { CaptureControl::from(read_volatile(mmio::DISPCAPCNT as *mut u32)) }
+}
+
+/// Captures one frame. The capture starts at the beginning of the next frame.
+///
+/// `enabled` is set automatically. Use [`is_capture_busy`] or [`wait_capture`] to check when it's finished.
+pub fn start_capture(c: CaptureControl) {
+    debug_assert!(c.eva() <= 16, "capture EVA must be from 0 to 16 (was: {})", c.eva());
+    debug_assert!(c.evb() <= 16, "capture EVB must be from 0 to 16 (was: {})", c.evb());
+    set_capture_control(c.with_enabled(true));
+}
+
+/// Checks if a capture is waiting to start or running.
+#[must_use]
+#[inline]
+pub fn is_capture_busy() -> bool {
+    get_capture_control().enabled()
+}
+
+/// Waits until the current capture has finished.
+pub fn wait_capture() {
+    while is_capture_busy() {}
+}
+
+/// Captures every frame, until [`stop_capture`] is called.
+///
+/// The capture has to be restarted every frame, by calling [`rearm_capture`] from your VBlank interrupt handler.
+///
+/// # Examples
+///
+/// ```
+/// irq_set_handler(IRQType::Vblank, rearm_capture);
+/// irq_enable(IRQFlags::VBLANK);
+/// // motion blur: blend the screen with the previous capture in bank C (vram_display_block must also be C)
+/// start_continuous_capture(CaptureControl::new()
+///     .with_source_a(CaptureSourceA::Graphics)
+///     .with_source_b(CaptureSourceB::Vram)
+///     .with_mode(CaptureMode::Blend)
+///     .with_eva(8)
+///     .with_evb(8)
+///     .with_size(CaptureSize::W256H192)
+///     .with_dest_block(VramDisplayBlock::C));
+/// ```
+pub fn start_continuous_capture(c: CaptureControl) {
+    let c = c.with_enabled(true);
+    CONTINUOUS_CAPTURE.write(u32::from(c));
+    start_capture(c);
+}
+
+/// Restarts the continuous capture for the next frame. Call this from your VBlank interrupt handler.
+///
+/// Does nothing if there's no continuous capture running.
+#[inline]
+pub fn rearm_capture() {
+    let control = CONTINUOUS_CAPTURE.read();
+    if control != 0 {
+        set_capture_control(CaptureControl::from(control));
+    }
+}
+
+/// Stops the continuous capture, and any capture that is waiting or running.
+pub fn stop_capture() {
+    CONTINUOUS_CAPTURE.write(0);
+    set_capture_control(get_capture_control().with_enabled(false));
+}
@@ -2,12 +2,13 @@
 //!
 //! This module is very limited, and should only really be used for debug purposes.
 
+use crate::display::palette::{Color, Palette};
 use crate::sync::{NdsCell, NdsCellSafe};
 use crate::{display, mmio};
 use bitfield_struct::bitfield;
 
 static DEFAULT_FONT: &[u8; 4096] = include_bytes!("../../gfx/font.img.bin");
-const DEFAULT_PALETTE: [u16; 2] = [display::rgb15(0x000000), display::rgb15(0xFFFFFF)];
+const DEFAULT_PALETTE: [Color; 2] = [Color::from_hex(0x000000), Color::from_hex(0xFFFFFF)];
 const TILES_PER_LINE: usize = 32;
 const BYTES_PER_LINE: usize = TILES_PER_LINE * 2;
 
@@ -30,10 +31,10 @@ pub fn init_default() {
     // set brightness to default level
     display::set_brightness(display::GfxEngine::SUB, 0);
 
-    display::map_vram_block_h(display::vram_type::H::SUB_BG);
+    display::map_vram_unchecked(display::vram_type::H::SUB_BG);
 
     display::set_sub_display_control(display::DisplayControlSub::new()
-        .with_bg_mode(0)
+        .with_bg_mode(display::BgModeSub::Mode0)
         .with_display_bg0(true)
         .with_display_bg1(false)
         .with_display_bg2(false)
@@ -41,19 +42,18 @@ pub fn init_default() {
         .with_display_obj(false)
         .with_display_win0(false)
         .with_display_win1(false)
-        .with_display_mode(1)
+        .with_display_mode(display::DisplayModeSub::Graphics)
         .with_bg_ext_pal_enabled(false));
 
     display::set_sub_bg_control(0, display::BackgroundControl::new()
         .with_priority(0)
         .with_tiledata_base(0)
         .with_mosaic_enabled(false)
-        .with_palette_setting(0)
+        .with_palette_setting(display::PaletteType::Colors16)
         .with_tilemap_base(4) // 8K offset
-        .with_screen_size(0));
+        .with_screen_size(display::ScreenSize::Size0));
 
-    mmio::BG0XOFS_SUB.write(0);
-    mmio::BG0YOFS_SUB.write(0);
+    display::bg::set_scroll(display::GfxEngine::SUB, 0, 0, 0);
 
     unsafe {
         // fill tilemap with space characters
@@ -66,15 +66,11 @@ pub fn init_default() {
             mmio::BG_RAM_BASE_SUB as *mut u16,
             DEFAULT_FONT.len() / 2,
         );
-
-        // load palette into sub-bg palette RAM
-        core::ptr::copy_nonoverlapping(
-            DEFAULT_PALETTE.as_ptr(),
-            mmio::BG_PALETTE_RAM_BASE_SUB as *mut u16,
-            DEFAULT_PALETTE.len(),
-        );
     }
 
+    // load palette into sub-bg palette RAM
+    Palette::bg(display::GfxEngine::SUB).write(0, &DEFAULT_PALETTE);
+
     set_cursor_pos(0, 0);
 }
 
@@ -0,0 +1,210 @@
+//! [`DrawTarget`] implementations, so `embedded-graphics` can draw to bitmap backgrounds and framebuffers.
+//!
+//! Direct colour surfaces ([`Framebuffer`] and [`DirectBitmapBg`]) use [`Rgb555`].
+//! Paletted bitmaps are drawn through [`PalettedBitmapBg`], with [`PaletteIndex`] colours.
+//! Bitmap backgrounds are wrapped in the type for their [`BitmapFormat`], so they can't be drawn with the wrong colours.
+//! Pixels outside of the surface are ignored. VRAM can't be written 8 bits at a time,
+//! so everything is written with 16 or 32 bit accesses.
+
+use super::bg::{BitmapBg, BitmapFormat};
+use super::framebuffer::{Framebuffer, HEIGHT, WIDTH};
+use super::palette::Color;
+use core::convert::Infallible;
+use embedded_graphics_core::pixelcolor::raw::RawU8;
+use embedded_graphics_core::pixelcolor::{PixelColor, Rgb555, RgbColor};
+use embedded_graphics_core::prelude::{DrawTarget, OriginDimensions, Pixel, Size};
+use embedded_graphics_core::primitives::Rectangle;
+
+impl From<Rgb555> for Color {
+    #[inline]
+    fn from(color: Rgb555) -> Self {
+        Color::from_rgb(color.r(), color.g(), color.b())
+    }
+}
+
+impl From<Color> for Rgb555 {
+    #[inline]
+    fn from(color: Color) -> Self {
+        Rgb555::new(color.r(), color.g(), color.b())
+    }
+}
+
+/// An index into the 256 colour BG palette, used to draw to a [`PalettedBitmapBg`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct PaletteIndex(pub u8);
+
+impl PixelColor for PaletteIndex {
+    type Raw = RawU8;
+}
+
+/// Gets the part of `area` that's inside a `width` x `height` surface, as (x, y, width, height).
+fn clip(area: &Rectangle, width: usize, height: usize) -> Option<(usize, usize, usize, usize)> {
+    let area = area.intersection(&Rectangle::new(Default::default(), Size::new(width as u32, height as u32)));
+    let bottom_right = area.bottom_right()?;
+    let (x, y) = (area.top_left.x as usize, area.top_left.y as usize);
+    Some((x, y, bottom_right.x as usize + 1 - x, bottom_right.y as usize + 1 - y))
+}
+
+/// Checks if a point is inside a `width` x `height` surface, and converts it to unsigned coordinates.
+#[inline(always)]
+fn inside(x: i32, y: i32, width: usize, height: usize) -> Option<(usize, usize)> {
+    ((0..width as i32).contains(&x) && (0..height as i32).contains(&y)).then_some((x as usize, y as usize))
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = Rgb555;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let Some((x, y)) = inside(point.x, point.y, WIDTH, HEIGHT) {
+                self.set_pixel(x, y, color.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.fill_rect(area.top_left.x, area.top_left.y, area.size.width, area.size.height, color.into());
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.fill(color.into());
+        Ok(())
+    }
+}
+
+/// A bitmap background with [`BitmapFormat::Direct`], drawn to with [`Rgb555`] colours.
+///
+/// Pixels are drawn with bit 15 set, so they're shown.
+#[derive(Debug, Clone, Copy)]
+pub struct DirectBitmapBg(BitmapBg);
+
+impl DirectBitmapBg {
+    /// Wraps `bitmap`, or returns `None` if it isn't a direct colour bitmap.
+    #[must_use]
+    #[inline]
+    pub fn new(bitmap: BitmapBg) -> Option<Self> {
+        (bitmap.format() == BitmapFormat::Direct).then_some(Self(bitmap))
+    }
+
+    /// The wrapped bitmap.
+    #[must_use]
+    #[inline]
+    pub const fn bitmap(&self) -> &BitmapBg {
+        &self.0
+    }
+}
+
+impl OriginDimensions for DirectBitmapBg {
+    fn size(&self) -> Size {
+        Size::new(self.0.width() as u32, self.0.height() as u32)
+    }
+}
+
+impl DrawTarget for DirectBitmapBg {
+    type Color = Rgb555;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let bitmap = &self.0;
+        for Pixel(point, color) in pixels {
+            if let Some((x, y)) = inside(point.x, point.y, bitmap.width(), bitmap.height()) {
+                bitmap.set_color(x, y, Color::from(color).opaque());
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let bitmap = &self.0;
+        let color = Color::from(color).opaque();
+        if let Some((x, y, width, height)) = clip(area, bitmap.width(), bitmap.height()) {
+            for row in y..y + height {
+                for col in x..x + width {
+                    bitmap.set_color(col, row, color);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.0.fill_color(Color::from(color).opaque());
+        Ok(())
+    }
+}
+
+/// A bitmap background with [`BitmapFormat::Paletted`], drawn to with palette indices.
+#[derive(Debug, Clone, Copy)]
+pub struct PalettedBitmapBg(BitmapBg);
+
+impl PalettedBitmapBg {
+    /// Wraps `bitmap`, or returns `None` if it isn't a paletted bitmap.
+    #[must_use]
+    #[inline]
+    pub fn new(bitmap: BitmapBg) -> Option<Self> {
+        (bitmap.format() == BitmapFormat::Paletted).then_some(Self(bitmap))
+    }
+
+    /// The wrapped bitmap.
+    #[must_use]
+    #[inline]
+    pub const fn bitmap(&self) -> &BitmapBg {
+        &self.0
+    }
+}
+
+impl OriginDimensions for PalettedBitmapBg {
+    fn size(&self) -> Size {
+        Size::new(self.0.width() as u32, self.0.height() as u32)
+    }
+}
+
+impl DrawTarget for PalettedBitmapBg {
+    type Color = PaletteIndex;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let bitmap = &self.0;
+        for Pixel(point, color) in pixels {
+            if let Some((x, y)) = inside(point.x, point.y, bitmap.width(), bitmap.height()) {
+                bitmap.set_index(x, y, color.0);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let bitmap = &self.0;
+        if let Some((x, y, width, height)) = clip(area, bitmap.width(), bitmap.height()) {
+            for row in y..y + height {
+                for col in x..x + width {
+                    bitmap.set_index(col, row, color.0);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.0.fill_index(color.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use super::GfxEngine;
+use crate::interrupt::critical_section;
+use crate::mmio;
+use bitflags::bitflags;
+use core::ptr::{read_volatile, write_volatile};
+
+// https://problemkaputt.de/gbatek.htm#lcdiowindowfeature
+// https://problemkaputt.de/gbatek.htm#lcdiomosaicfunction
+// https://problemkaputt.de/gbatek.htm#lcdiocolorspecialeffects
+
+bitflags! {
+    /// What is shown inside a window.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct WindowLayers: u8 {
+        const BG0 = 1 << 0;
+        const BG1 = 1 << 1;
+        const BG2 = 1 << 2;
+        const BG3 = 1 << 3;
+        const OBJ = 1 << 4;
+        /// Blending and brightness effects are applied inside the window
+        const EFFECTS = 1 << 5;
+        const ALL_BG = Self::BG0.bits() | Self::BG1.bits() | Self::BG2.bits() | Self::BG3.bits();
+        const ALL = Self::ALL_BG.bits() | Self::OBJ.bits() | Self::EFFECTS.bits();
+    }
+}
+
+bitflags! {
+    /// Layers that are used as blending targets.
+    #[repr(transparent)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct BlendLayers: u8 {
+        const BG0 = 1 << 0;
+        const BG1 = 1 << 1;
+        const BG2 = 1 << 2;
+        const BG3 = 1 << 3;
+        const OBJ = 1 << 4;
+        /// The backdrop colour (colour 0 of the BG palette), shown where no layer is
+        const BACKDROP = 1 << 5;
+        const ALL_BG = Self::BG0.bits() | Self::BG1.bits() | Self::BG2.bits() | Self::BG3.bits();
+        const ALL = Self::ALL_BG.bits() | Self::OBJ.bits() | Self::BACKDROP.bits();
+    }
+}
+
+/// One of the two rectangular windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Win0 = 0,
+    Win1 = 1,
+}
+
+/// The colour effect applied to the first target layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    None = 0,
+    /// Mixes the first target with the second target below it (see [`set_blend_alpha`])
+    Alpha = 1,
+    /// Fades the first target towards white (see [`set_blend_brightness`])
+    Brighten = 2,
+    /// Fades the first target towards black (see [`set_blend_brightness`])
+    Darken = 3,
+}
+
+#[inline(always)]
+fn reg(main_addr: usize, engine: GfxEngine) -> *mut u16 {
+    (main_addr + engine as usize) as *mut u16
+}
+
+/// Sets the rectangle covered by one of the windows.
+///
+/// `right` and `bottom` are exclusive, so the pixel at `right` isn't inside the window.
+/// If `left` is greater than `right` (or `top` is greater than `bottom`), the window wraps around the edge of the screen.
+/// The window also has to be turned on in the display control, with `display_win0` or `display_win1`.
+#[inline]
+pub fn set_window_rect(engine: GfxEngine, window: Window, left: u8, top: u8, right: u8, bottom: u8) {
+    let offset = window as usize * 2;
+    unsafe {
+        write_volatile(reg(mmio::WIN0H_MAIN.as_usize() + offset, engine), (left as u16) << 8 | right as u16);
+        write_volatile(reg(mmio::WIN0V_MAIN.as_usize() + offset, engine), (top as u16) << 8 | bottom as u16);
+    }
+}
+
+/// Changes one half of WININ or WINOUT.
+fn set_window_half(addr: *mut u16, high: bool, layers: WindowLayers) {
+    let shift = if high { 8 } else { 0 };
+    critical_section!({
+        unsafe {
+            let old = read_volatile(addr) & !(0x3F << shift);
+            write_volatile(addr, old | (layers.bits() as u16) << shift);
+        }
+    });
+}
+
+/// Sets what is shown inside one of the rectangular windows.
+#[inline]
+pub fn set_window_layers(engine: GfxEngine, window: Window, layers: WindowLayers) {
+    set_window_half(reg(mmio::WININ_MAIN.as_usize(), engine), window == Window::Win1, layers);
+}
+
+/// Sets what is shown inside the OBJ window (the shape of the sprites with [`ObjMode::Window`](super::obj::ObjMode::Window)).
+#[inline]
+pub fn set_obj_window_layers(engine: GfxEngine, layers: WindowLayers) {
+    set_window_half(reg(mmio::WINOUT_MAIN.as_usize(), engine), true, layers);
+}
+
+/// Sets what is shown outside of all the enabled windows.
+#[inline]
+pub fn set_outside_window_layers(engine: GfxEngine, layers: WindowLayers) {
+    set_window_half(reg(mmio::WINOUT_MAIN.as_usize(), engine), false, layers);
+}
+
+/// Sets the colour effect, and which layers it uses.
+///
+/// `first` is the layers the effect is applied to. `second` is only used by [`BlendMode::Alpha`],
+/// and is the layers that the first target can be blended with (if they are right underneath it).
+/// Semi-transparent sprites always blend with the second target, even when the mode is something else.
+#[inline]
+pub fn set_blend(engine: GfxEngine, mode: BlendMode, first: BlendLayers, second: BlendLayers) {
+    let value = first.bits() as u16 | (mode as u16) << 6 | (second.bits() as u16) << 8;
+    unsafe { write_volatile(reg(mmio::BLDCNT_MAIN.as_usize(), engine), value); }
+}
+
+/// Sets the weights used by [`BlendMode::Alpha`], from 0 to 16.
+///
+/// The result is `first * eva / 16 + second * evb / 16`.
+#[inline]
+pub fn set_blend_alpha(engine: GfxEngine, eva: u8, evb: u8) {
+    debug_assert!(eva <= 16, "blend EVA must be from 0 to 16 (was: {eva})");
+    debug_assert!(evb <= 16, "blend EVB must be from 0 to 16 (was: {evb})");
+    unsafe { write_volatile(reg(mmio::BLDALPHA_MAIN.as_usize(), engine), (eva as u16 & 0x1F) | (evb as u16 & 0x1F) << 8); }
+}
+
+/// Sets how strong [`BlendMode::Brighten`] and [`BlendMode::Darken`] are, from 0 (no change) to 16 (fully white / black).
+#[inline]
+pub fn set_blend_brightness(engine: GfxEngine, evy: u8) {
+    debug_assert!(evy <= 16, "blend EVY must be from 0 to 16 (was: {evy})");
+    unsafe { write_volatile(reg(mmio::BLDY_MAIN.as_usize(), engine), evy as u16 & 0x1F); }
+}
+
+/// Sets the size of the mosaic blocks, in pixels (1 to 16, 1 = no mosaic).
+///
+/// Only affects backgrounds with `mosaic_enabled` set, and sprites with `mosaic` set.
+#[inline]
+pub fn set_mosaic(engine: GfxEngine, bg_width: u8, bg_height: u8, obj_width: u8, obj_height: u8) {
+    for size in [bg_width, bg_height, obj_width, obj_height] {
+        debug_assert!(size >= 1 && size <= 16, "mosaic size must be from 1 to 16 (was: {size})");
+    }
+    let field = |size: u8| (size.saturating_sub(1) & 0xF) as u16;
+    let value = field(bg_width) | field(bg_height) << 4 | field(obj_width) << 8 | field(obj_height) << 12;
+    unsafe { write_volatile(reg(mmio::MOSAIC_MAIN.as_usize(), engine), value); }
+}
@@ -0,0 +1,293 @@
+//! Module for drawing straight to the screen, using a VRAM bank as a 256x192 framebuffer.
+//!
+//! The main engine can show one of banks A-D directly (with [`DisplayModeMain::Vram`]), without any backgrounds or sprites.
+//! The bank has to be mapped to LCDC, and the mapping returned by `map_vram(vram_type::A::LCDC)` is passed to [`Framebuffer::new`].
+
+use super::palette::Color;
+use super::{get_main_display_control, set_main_display_control, DisplayModeMain, VramBank, VramDisplayBlock, VramMapping, VramRegion};
+use crate::interrupt::{critical_section, wait_for_vblank};
+use core::mem::swap;
+use core::ptr::{read_volatile, write_volatile};
+
+// https://problemkaputt.de/gbatek.htm#dsvideodisplaysystemblockdiagram
+
+/// Width of the screen, in pixels.
+pub const WIDTH: usize = 256;
+/// Height of the screen, in pixels.
+pub const HEIGHT: usize = 192;
+
+/// A VRAM bank (A-D) used as a framebuffer.
+///
+/// Drawing functions that take `i32` coordinates clip to the edges, so shapes can be partly off-screen.
+/// Bit 15 of the colours is ignored in this mode.
+#[derive(Debug)]
+pub struct Framebuffer {
+    block: VramDisplayBlock,
+}
+
+#[inline(always)]
+const fn display_block_bank(block: VramDisplayBlock) -> VramBank {
+    match block {
+        VramDisplayBlock::A => VramBank::A,
+        VramDisplayBlock::B => VramBank::B,
+        VramDisplayBlock::C => VramBank::C,
+        VramDisplayBlock::D => VramBank::D,
+    }
+}
+
+impl Framebuffer {
+    /// Uses the bank of `mapping` as a framebuffer.
+    ///
+    /// Returns `None` if `mapping` isn't bank A-D mapped to LCDC, or the bank has been mapped somewhere else since.
+    #[must_use]
+    pub fn new(mapping: VramMapping) -> Option<Self> {
+        let block = match mapping.bank {
+            VramBank::A => VramDisplayBlock::A,
+            VramBank::B => VramDisplayBlock::B,
+            VramBank::C => VramDisplayBlock::C,
+            VramBank::D => VramDisplayBlock::D,
+            _ => return None,
+        };
+        (mapping.region == VramRegion::Lcdc && mapping.bank.mapping() == Some(mapping)).then_some(Self { block })
+    }
+
+    /// The bank used by this framebuffer.
+    #[must_use]
+    #[inline]
+    pub const fn block(&self) -> VramDisplayBlock {
+        self.block
+    }
+
+    /// Address of the top left pixel.
+    #[must_use]
+    #[inline]
+    pub const fn addr(&self) -> usize {
+        display_block_bank(self.block).lcdc_addr()
+    }
+
+    #[inline(always)]
+    fn ptr(&self) -> *mut u16 {
+        self.addr() as *mut u16
+    }
+
+    /// Shows this framebuffer on the main engine's screen, by switching it to [`DisplayModeMain::Vram`].
+    ///
+    /// This takes effect straight away, so the change can happen in the middle of a frame. Use [`DoubleBuffer`] to avoid that.
+    pub fn show(&self) {
+        critical_section!({
+            set_main_display_control(get_main_display_control()
+                .with_display_mode(DisplayModeMain::Vram)
+                .with_vram_display_block(self.block));
+        });
+    }
+
+    /// Sets one pixel.
+    #[inline]
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        assert!(x < WIDTH && y < HEIGHT, "pixel ({x}, {y}) is outside the screen");
+        unsafe { write_volatile(self.ptr().add(y * WIDTH + x), color.to_bits()); }
+    }
+
+    /// Gets one pixel.
+    #[must_use]
+    #[inline]
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        assert!(x < WIDTH && y < HEIGHT, "pixel ({x}, {y}) is outside the screen");
+        unsafe { Color::from_bits(read_volatile(self.ptr().add(y * WIDTH + x))) }
+    }
+
+    /// Sets one pixel, if it's on the screen.
+    #[inline]
+    fn plot(&mut self, x: i32, y: i32, color: Color) {
+        if (0..WIDTH as i32).contains(&x) && (0..HEIGHT as i32).contains(&y) {
+            self.set_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    /// Sets every pixel to `color`.
+    pub fn fill(&mut self, color: Color) {
+        let value = color.to_bits() as u32 * 0x0001_0001;
+        let ptr = self.addr() as *mut u32;
+        for i in 0..WIDTH * HEIGHT / 2 {
+            unsafe { write_volatile(ptr.add(i), value); }
+        }
+    }
+
+    /// Fills a rectangle with `color`.
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: Color) {
+        let (x0, x1) = (x.clamp(0, WIDTH as i32), (x as i64 + width as i64).clamp(0, WIDTH as i64) as i32);
+        let (y0, y1) = (y.clamp(0, HEIGHT as i32), (y as i64 + height as i64).clamp(0, HEIGHT as i64) as i32);
+        for row in y0..y1 {
+            let line = unsafe { self.ptr().add(row as usize * WIDTH) };
+            for col in x0..x1 {
+                unsafe { write_volatile(line.add(col as usize), color.to_bits()); }
+            }
+        }
+    }
+
+    /// Draws the outline of a rectangle, 1 pixel wide.
+    pub fn rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: Color) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        // the far edges might not fit in an i32, in which case they're off the screen anyway
+        let right = (x as i64 + width as i64 - 1).min(i32::MAX as i64) as i32;
+        let bottom = (y as i64 + height as i64 - 1).min(i32::MAX as i64) as i32;
+        self.fill_rect(x, y, width, 1, color);
+        self.fill_rect(x, bottom, width, 1, color);
+        self.fill_rect(x, y, 1, height, color);
+        self.fill_rect(right, y, 1, height, color);
+    }
+
+    /// Draws a line from (`x0`, `y0`) to (`x1`, `y1`), including both ends.
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+        // only step along the part that's on the screen, so far away ends don't take forever
+        let Some((mut x0, mut y0, x1, y1)) = clip_line(x0 as i64, y0 as i64, x1 as i64, y1 as i64) else { return; };
+        // https://en.wikipedia.org/wiki/Bresenham%27s_line_algorithm
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        loop {
+            self.plot(x0, y0, color);
+            if x0 == x1 && y0 == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+
+    /// Copies an image onto the framebuffer, with its top left corner at (`x`, `y`).
+    ///
+    /// `pixels` is the image in rows of `width` pixels.
+    pub fn blit(&mut self, x: i32, y: i32, width: usize, pixels: &[Color]) {
+        if width == 0 {
+            return;
+        }
+        for (row, line) in pixels.chunks(width).enumerate() {
+            let py = y as i64 + row as i64;
+            if !(0..HEIGHT as i64).contains(&py) {
+                continue;
+            }
+            let first = x.saturating_neg().max(0) as usize;
+            let last = (WIDTH as i64 - x as i64).clamp(0, line.len() as i64) as usize;
+            let dest = unsafe { self.ptr().add(py as usize * WIDTH) };
+            for (col, &color) in line.iter().enumerate().take(last).skip(first) {
+                unsafe { write_volatile(dest.add((x + col as i32) as usize), color.to_bits()); }
+            }
+        }
+    }
+}
+
+/// Clips the line from (`x0`, `y0`) to (`x1`, `y1`) to the screen, using the Cohen-Sutherland algorithm.
+///
+/// Returns `None` if none of the line is on the screen.
+fn clip_line(mut x0: i64, mut y0: i64, mut x1: i64, mut y1: i64) -> Option<(i32, i32, i32, i32)> {
+    // https://en.wikipedia.org/wiki/Cohen%E2%80%93Sutherland_algorithm
+    const LEFT: u8 = 1;
+    const RIGHT: u8 = 2;
+    const TOP: u8 = 4;
+    const BOTTOM: u8 = 8;
+    let (right, bottom) = (WIDTH as i64 - 1, HEIGHT as i64 - 1);
+    let outcode = |x: i64, y: i64| {
+        let horizontal = if x < 0 { LEFT } else if x > right { RIGHT } else { 0 };
+        let vertical = if y < 0 { TOP } else if y > bottom { BOTTOM } else { 0 };
+        horizontal | vertical
+    };
+    // the position along the line where `a` reaches `edge`, in the other coordinate.
+    // the product can be bigger than an i64
+    let intersect = |a0: i64, b0: i64, a1: i64, b1: i64, edge: i64| {
+        (b0 as i128 + (b1 - b0) as i128 * (edge - a0) as i128 / (a1 - a0) as i128) as i64
+    };
+    let (mut code0, mut code1) = (outcode(x0, y0), outcode(x1, y1));
+    loop {
+        if code0 | code1 == 0 {
+            return Some((x0 as i32, y0 as i32, x1 as i32, y1 as i32));
+        }
+        if code0 & code1 != 0 {
+            return None;
+        }
+        // move an end that's off the screen onto the edge it's past
+        let code = if code0 != 0 { code0 } else { code1 };
+        let (x, y) = if code & TOP != 0 {
+            (intersect(y0, x0, y1, x1, 0), 0)
+        } else if code & BOTTOM != 0 {
+            (intersect(y0, x0, y1, x1, bottom), bottom)
+        } else if code & LEFT != 0 {
+            (0, intersect(x0, y0, x1, y1, 0))
+        } else {
+            (right, intersect(x0, y0, x1, y1, right))
+        };
+        if code == code0 {
+            (x0, y0, code0) = (x, y, outcode(x, y));
+        } else {
+            (x1, y1, code1) = (x, y, outcode(x, y));
+        }
+    }
+}
+
+/// Two framebuffers, one shown while the other is drawn to.
+///
+/// # Examples
+///
+/// ```
+/// let a = map_vram(vram_type::A::LCDC)?;
+/// let b = map_vram(vram_type::B::LCDC)?;
+/// let mut fb = DoubleBuffer::new(a, b).unwrap();
+/// loop {
+///     fb.back().fill(Color::BLACK);
+///     fb.back().line(0, 0, 255, 191, Color::WHITE);
+///     fb.flip();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct DoubleBuffer {
+    front: Framebuffer,
+    back: Framebuffer,
+}
+
+impl DoubleBuffer {
+    /// Uses two banks as framebuffers, and shows the first one.
+    ///
+    /// Returns `None` if either mapping can't be used by [`Framebuffer::new`], or they're the same bank.
+    #[must_use]
+    pub fn new(first: VramMapping, second: VramMapping) -> Option<Self> {
+        if first.bank == second.bank {
+            return None;
+        }
+        let front = Framebuffer::new(first)?;
+        let back = Framebuffer::new(second)?;
+        front.show();
+        Some(Self { front, back })
+    }
+
+    /// The framebuffer that isn't being shown, to draw the next frame into.
+    #[must_use]
+    #[inline]
+    pub fn back(&mut self) -> &mut Framebuffer {
+        &mut self.back
+    }
+
+    /// The framebuffer that is being shown.
+    #[must_use]
+    #[inline]
+    pub fn front(&self) -> &Framebuffer {
+        &self.front
+    }
+
+    /// Waits for VBlank, then shows the back buffer. The old front buffer becomes the new back buffer.
+    ///
+    /// Make sure interrupts are enabled before calling this!
+    pub fn flip(&mut self) {
+        wait_for_vblank();
+        self.back.show();
+        swap(&mut self.front, &mut self.back);
+    }
+}
@@ -0,0 +1,177 @@
+//! Module for showing images straight from main RAM, using the main memory display FIFO.
+//!
+//! In [`DisplayModeMain::MainMemory`], the main engine's screen shows pixels written to the display FIFO,
+//! which a DMA channel keeps filled from a frame in main RAM. This doesn't use any VRAM banks,
+//! but the DMA channel is busy for the whole frame, and the backgrounds and sprites aren't shown.
+
+use super::framebuffer::{HEIGHT, WIDTH};
+use super::palette::Color;
+use super::{get_main_display_control, set_main_display_control, DisplayModeMain};
+use crate::cache;
+use crate::dma::{AddrControl, DmaChannel, DmaControl, DmaTiming, DmaUnit};
+use crate::interrupt::{critical_section, wait_for_vblank};
+use crate::mmio;
+use crate::sync::NdsCell;
+use alloc::alloc::{alloc_zeroed, handle_alloc_error, Layout};
+use alloc::boxed::Box;
+use core::mem::{size_of, swap};
+
+// https://problemkaputt.de/gbatek.htm#dsvideocaptureandmainmemorydisplaymode
+
+// Each FIFO request is for 8 pixels (4 words)
+const WORDS_PER_REQUEST: u32 = 4;
+const NOT_STREAMING: u32 = 0;
+
+// Address of the frame that's streamed from the next VBlank, or NOT_STREAMING
+static STREAM_ADDR: NdsCell<u32> = NdsCell::new(NOT_STREAMING);
+static STREAM_CHANNEL: NdsCell<u8> = NdsCell::new(0);
+
+/// A 256x192 image in main RAM, that can be streamed to the screen.
+#[repr(C, align(32))]
+pub struct MainMemoryFrame {
+    /// The pixels, in rows from the top left. Bit 15 is ignored.
+    pub pixels: [Color; WIDTH * HEIGHT],
+}
+
+impl MainMemoryFrame {
+    /// Allocates a frame, with all the pixels black.
+    ///
+    /// The frame is 96K, so it's allocated straight on the heap.
+    #[must_use]
+    pub fn new_boxed() -> Box<Self> {
+        let layout = Layout::new::<Self>();
+        unsafe {
+            // all zeroes is a valid frame (every pixel is black)
+            let ptr = alloc_zeroed(layout) as *mut Self;
+            if ptr.is_null() {
+                handle_alloc_error(layout);
+            }
+            Box::from_raw(ptr)
+        }
+    }
+
+    /// Sets one pixel.
+    #[inline]
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        assert!(x < WIDTH && y < HEIGHT, "pixel ({x}, {y}) is outside the screen");
+        self.pixels[y * WIDTH + x] = color;
+    }
+
+    /// Gets one pixel.
+    #[must_use]
+    #[inline]
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        assert!(x < WIDTH && y < HEIGHT, "pixel ({x}, {y}) is outside the screen");
+        self.pixels[y * WIDTH + x]
+    }
+
+    /// Sets every pixel to `color`.
+    #[inline]
+    pub fn fill(&mut self, color: Color) {
+        self.pixels.fill(color);
+    }
+}
+
+/// Restarts streaming at the top of the frame. Call this at the start of your VBlank interrupt handler.
+///
+/// Does nothing if there's no [`MainMemoryDisplay`].
+pub fn restart_stream() {
+    let addr = STREAM_ADDR.read();
+    // once streaming has stopped, the channel might be used for something else
+    if addr == NOT_STREAMING {
+        return;
+    }
+    let channel = DmaChannel::from_index(STREAM_CHANNEL.read() as u32);
+    channel.stop();
+    let control = DmaControl::new()
+        .with_unit_count(WORDS_PER_REQUEST)
+        .with_dest_control(AddrControl::Fixed)
+        .with_src_control(AddrControl::Increment)
+        .with_repeat(true)
+        .with_unit(DmaUnit::Bits32)
+        .with_timing(DmaTiming::MainMemoryDisplay);
+    // the frame stays allocated while its address is in STREAM_ADDR
+    unsafe { channel.start(addr as *const u8, mmio::DISP_MMEM_FIFO as *mut u8, control); }
+}
+
+/// Streams frames from main RAM to the main engine's screen, with double buffering.
+///
+/// One frame is shown while the other one is drawn to. The DMA has to be restarted every frame,
+/// by calling [`restart_stream`] from your VBlank interrupt handler.
+///
+/// # Examples
+///
+/// ```
+/// irq_set_handler(IRQType::Vblank, restart_stream);
+/// irq_enable(IRQFlags::VBLANK);
+/// let mut display = MainMemoryDisplay::new(DmaChannel::Dma0);
+/// loop {
+///     display.back().fill(Color::BLACK);
+///     display.back().set_pixel(128, 96, Color::WHITE);
+///     display.present();
+/// }
+/// ```
+pub struct MainMemoryDisplay {
+    channel: DmaChannel,
+    front: Box<MainMemoryFrame>,
+    back: Box<MainMemoryFrame>,
+}
+
+impl MainMemoryDisplay {
+    /// Switches the main engine to [`DisplayModeMain::MainMemory`], and starts streaming a black frame using `channel`.
+    ///
+    /// The channel shouldn't be used for anything else until this is dropped. Only one `MainMemoryDisplay` can exist at once.
+    /// Streaming starts at the next call to [`restart_stream`].
+    ///
+    /// # Panics
+    /// Panics if another `MainMemoryDisplay` exists.
+    #[must_use]
+    pub fn new(channel: DmaChannel) -> Self {
+        assert!(STREAM_ADDR.read() == NOT_STREAMING, "only one MainMemoryDisplay can exist at once");
+        let front = MainMemoryFrame::new_boxed();
+        let back = MainMemoryFrame::new_boxed();
+        cache::clean_dcache_range(&*front as *const _ as *const u8, size_of::<MainMemoryFrame>());
+
+        critical_section!({
+            STREAM_CHANNEL.write(channel as u8);
+            STREAM_ADDR.write(&*front as *const _ as u32);
+            set_main_display_control(get_main_display_control().with_display_mode(DisplayModeMain::MainMemory));
+        });
+        Self { channel, front, back }
+    }
+
+    /// The frame that isn't being shown, to draw the next frame into.
+    #[must_use]
+    #[inline]
+    pub fn back(&mut self) -> &mut MainMemoryFrame {
+        &mut self.back
+    }
+
+    /// The frame that is being shown.
+    #[must_use]
+    #[inline]
+    pub fn front(&self) -> &MainMemoryFrame {
+        &self.front
+    }
+
+    /// Shows the back frame from the next VBlank, and waits for it. The old front frame becomes the new back frame.
+    ///
+    /// Make sure interrupts are enabled before calling this!
+    pub fn present(&mut self) {
+        // the DMA reads main RAM directly, so the frame can't be left in the cache
+        cache::clean_dcache_range(&*self.back as *const _ as *const u8, size_of::<MainMemoryFrame>());
+        STREAM_ADDR.write(&*self.back as *const _ as u32);
+        wait_for_vblank();
+        swap(&mut self.front, &mut self.back);
+    }
+}
+
+impl Drop for MainMemoryDisplay {
+    /// Stops streaming. The screen keeps showing the last pixels in the FIFO until the display mode is changed.
+    fn drop(&mut self) {
+        critical_section!({
+            STREAM_ADDR.write(NOT_STREAMING);
+            self.channel.stop();
+        });
+    }
+}
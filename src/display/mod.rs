@@ -1,5 +1,15 @@
+pub mod bg;
+pub mod capture;
 pub mod console;
+#[cfg(feature = "embedded-graphics")]
+pub mod draw_target;
+pub mod effects;
+pub mod framebuffer;
+pub mod main_memory;
 pub mod obj;
+pub mod obj_alloc;
+pub mod palette;
+pub mod vram_alloc;
 mod vram;
 pub use vram::*;
 
@@ -26,14 +36,166 @@ bitflags! {
     }
 }
 
+// Conversions needed to use an enum as a bitfield field.
+// Invalid values (which can only come from raw register values) are converted to `$fallback`.
+pub(crate) macro bitfield_enum($name:ident: $base:ty { $($val:literal => $variant:ident),+ $(,)? } else $fallback:ident) {
+    impl From<$base> for $name {
+        #[inline]
+        fn from(value: $base) -> Self {
+            match value {
+                $($val => Self::$variant,)+
+                _ => Self::$fallback,
+            }
+        }
+    }
+
+    impl From<$name> for $base {
+        #[inline]
+        fn from(value: $name) -> Self {
+            value as $base
+        }
+    }
+}
+
+/// Background mode of the main engine. Says what type each background layer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BgModeMain {
+    /// BG0-BG3 text
+    Mode0 = 0,
+    /// BG0-BG2 text, BG3 affine
+    Mode1 = 1,
+    /// BG0-BG1 text, BG2-BG3 affine
+    Mode2 = 2,
+    /// BG0-BG2 text, BG3 extended
+    Mode3 = 3,
+    /// BG0-BG1 text, BG2 affine, BG3 extended
+    Mode4 = 4,
+    /// BG0-BG1 text, BG2-BG3 extended
+    Mode5 = 5,
+    /// BG2 large bitmap (BG0 can still be 3D)
+    Mode6 = 6,
+}
+bitfield_enum!(BgModeMain: u32 { 0 => Mode0, 1 => Mode1, 2 => Mode2, 3 => Mode3, 4 => Mode4, 5 => Mode5, 6 => Mode6 } else Mode0);
+
+/// Background mode of the sub engine. Same as [`BgModeMain`], but without the large bitmap mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BgModeSub {
+    /// BG0-BG3 text
+    Mode0 = 0,
+    /// BG0-BG2 text, BG3 affine
+    Mode1 = 1,
+    /// BG0-BG1 text, BG2-BG3 affine
+    Mode2 = 2,
+    /// BG0-BG2 text, BG3 extended
+    Mode3 = 3,
+    /// BG0-BG1 text, BG2 affine, BG3 extended
+    Mode4 = 4,
+    /// BG0-BG1 text, BG2-BG3 extended
+    Mode5 = 5,
+}
+bitfield_enum!(BgModeSub: u32 { 0 => Mode0, 1 => Mode1, 2 => Mode2, 3 => Mode3, 4 => Mode4, 5 => Mode5 } else Mode0);
+
+/// What the main engine sends to its screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayModeMain {
+    /// Screen is white
+    Off = 0,
+    /// Normal backgrounds and sprites
+    Graphics = 1,
+    /// Bitmap straight from the VRAM bank set by `vram_display_block`
+    Vram = 2,
+    /// Bitmap streamed from main memory with DMA
+    MainMemory = 3,
+}
+bitfield_enum!(DisplayModeMain: u32 { 0 => Off, 1 => Graphics, 2 => Vram, 3 => MainMemory } else Off);
+
+/// What the sub engine sends to its screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayModeSub {
+    /// Screen is white
+    Off = 0,
+    /// Normal backgrounds and sprites
+    Graphics = 1,
+}
+bitfield_enum!(DisplayModeSub: u32 { 0 => Off, 1 => Graphics } else Off);
+
+/// How tiles for tiled sprites are found in VRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjTileMapping {
+    /// Tiles are arranged in a 32x32 tile grid
+    TwoD = 0,
+    /// Tiles of each sprite come one after another, in steps set by `tile_obj_1d_bound`
+    OneD = 1,
+}
+bitfield_enum!(ObjTileMapping: u32 { 0 => TwoD, 1 => OneD } else TwoD);
+
+/// Width of the bitmap area for bitmap sprites in 2D mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapObj2dDim {
+    Width128 = 0,
+    Width256 = 1,
+}
+bitfield_enum!(BitmapObj2dDim: u32 { 0 => Width128, 1 => Width256 } else Width128);
+
+/// How bitmaps for bitmap sprites are found in VRAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapObjMapping {
+    /// Bitmaps are arranged in a 2D area, with the width from `bm_obj_2d_dim`
+    TwoD = 0,
+    /// Bitmaps come one after another, in steps set by `bm_obj_1d_bound`
+    OneD = 1,
+}
+bitfield_enum!(BitmapObjMapping: u32 { 0 => TwoD, 1 => OneD } else TwoD);
+
+/// Which VRAM bank is shown in [`DisplayModeMain::Vram`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VramDisplayBlock {
+    A = 0,
+    B = 1,
+    C = 2,
+    D = 3,
+}
+bitfield_enum!(VramDisplayBlock: u32 { 0 => A, 1 => B, 2 => C, 3 => D } else A);
+
+/// Colour format of tiles, for backgrounds and sprites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteType {
+    /// 4 bits per pixel, with 16 palettes of 16 colours
+    Colors16 = 0,
+    /// 8 bits per pixel, with 1 palette of 256 colours (or extended palettes)
+    Colors256 = 1,
+}
+bitfield_enum!(PaletteType: u16 { 0 => Colors16, 1 => Colors256 } else Colors16);
+bitfield_enum!(PaletteType: u64 { 0 => Colors16, 1 => Colors256 } else Colors16);
+
+/// Size of a background. What each size means depends on the type of the background:
+///
+/// | Size  | Text    | Affine    | Bitmap  |
+/// |-------|---------|-----------|---------|
+/// | Size0 | 256x256 | 128x128   | 128x128 |
+/// | Size1 | 512x256 | 256x256   | 256x256 |
+/// | Size2 | 256x512 | 512x512   | 512x256 |
+/// | Size3 | 512x512 | 1024x1024 | 512x512 |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenSize {
+    Size0 = 0,
+    Size1 = 1,
+    Size2 = 2,
+    Size3 = 3,
+}
+bitfield_enum!(ScreenSize: u16 { 0 => Size0, 1 => Size1, 2 => Size2, 3 => Size3 } else Size0);
+
 #[bitfield(u32)]
 pub struct DisplayControlMain {
     #[bits(3)]
-    pub bg_mode: u8, // enum
+    pub bg_mode: BgModeMain,
     pub bg0_3d: bool,
-    pub tile_obj_mapping: bool, // enum
-    pub bm_obj_2d_dim: bool,    // enum
-    pub bm_obj_mapping: bool,   // enum
+    #[bits(1)]
+    pub tile_obj_mapping: ObjTileMapping,
+    #[bits(1)]
+    pub bm_obj_2d_dim: BitmapObj2dDim,
+    #[bits(1)]
+    pub bm_obj_mapping: BitmapObjMapping,
     pub forced_blank: bool,
     pub display_bg0: bool,
     pub display_bg1: bool,
@@ -44,9 +206,9 @@ pub struct DisplayControlMain {
     pub display_win1: bool,
     pub display_obj_win: bool,
     #[bits(2)]
-    pub display_mode: u8, // enum
+    pub display_mode: DisplayModeMain,
     #[bits(2)]
-    pub vram_display_block: u8, // enum
+    pub vram_display_block: VramDisplayBlock,
     #[bits(2)]
     pub tile_obj_1d_bound: u8,
     #[bits(1)]
@@ -60,14 +222,20 @@ pub struct DisplayControlMain {
     pub obj_ext_pal_enabled: bool,
 }
 
+// Bits of the display control that only do something on the main engine
+const SUB_UNUSED_BITS: u32 = (1 << 3) | (0x3 << 18) | (1 << 22) | (0x3F << 24);
+
 #[bitfield(u32)]
 pub struct DisplayControlSub {
     #[bits(3)]
-    pub bg_mode: u8, // enum (different)
+    pub bg_mode: BgModeSub,
     _p: bool,
-    pub tile_obj_mapping: bool, // enum
-    pub bm_obj_2d_dim: bool,    // enum
-    pub bm_obj_mapping: bool,   // enum
+    #[bits(1)]
+    pub tile_obj_mapping: ObjTileMapping,
+    #[bits(1)]
+    pub bm_obj_2d_dim: BitmapObj2dDim,
+    #[bits(1)]
+    pub bm_obj_mapping: BitmapObjMapping,
     pub forced_blank: bool,
     pub display_bg0: bool,
     pub display_bg1: bool,
@@ -78,7 +246,7 @@ pub struct DisplayControlSub {
     pub display_win1: bool,
     pub display_obj_win: bool,
     #[bits(2)]
-    pub display_mode: u8, // enum (different)
+    pub display_mode: DisplayModeSub,
     #[bits(2)]
     _p: u8,
     #[bits(2)]
@@ -91,6 +259,39 @@ pub struct DisplayControlSub {
     pub obj_ext_pal_enabled: bool,
 }
 
+impl DisplayControlMain {
+    /// Converts a raw register value, returning `None` if it has a value that isn't valid on the main engine.
+    ///
+    /// Every display mode exists on the main engine, so the only invalid value is bg mode 7.
+    #[must_use]
+    pub fn try_from_bits(bits: u32) -> Option<Self> {
+        // bg mode 7 is the only invalid value
+        if bits & 0x7 == 0x7 {
+            return None;
+        }
+        Some(Self::from(bits))
+    }
+}
+
+impl DisplayControlSub {
+    /// Converts a raw register value, returning `None` if it has a value that isn't valid on the sub engine.
+    ///
+    /// That is bg modes 6 and 7, display modes 2 and 3, and any of the main engine only bits
+    /// (BG0 3D, VRAM display block, bitmap sprite 1D boundary, and the master tiledata / tilemap bases).
+    #[must_use]
+    pub fn try_from_bits(bits: u32) -> Option<Self> {
+        if bits & SUB_UNUSED_BITS != 0 {
+            return None;
+        }
+        let c = Self::from(bits);
+        // bg modes 6 and 7, and display modes 2 and 3 don't exist on the sub engine
+        if u32::from(c.bg_mode()) != bits & 0x7 || u32::from(c.display_mode()) != (bits >> 16) & 0x3 {
+            return None;
+        }
+        Some(c)
+    }
+}
+
 #[bitfield(u16)]
 pub struct BackgroundControl {
     #[bits(2)]
@@ -99,13 +300,13 @@ pub struct BackgroundControl {
     pub tiledata_base: u8,
     pub mosaic_enabled: bool,
     #[bits(1)]
-    pub palette_setting: u8, // enum
+    pub palette_setting: PaletteType,
     #[bits(5)]
     pub tilemap_base: u8,
     #[bits(1)]
     pub bit13: u8, // BG0/BG1 = Ext Palette Slot. BG2/BG3 = Display Area Overflow (0=Transparent, 1=Wraparound)
     #[bits(2)]
-    pub screen_size: u8,
+    pub screen_size: ScreenSize,
 }
 
 #[derive(Clone, Copy)]
@@ -114,7 +315,7 @@ pub enum MainEnginePos {
     BOTTOM = 0,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GfxEngine {
     MAIN = 0,
     SUB = 0x1000,
@@ -1,6 +1,9 @@
-use super::GfxEngine;
-use crate::mmio;
+use super::{bitfield_enum, GfxEngine, PaletteType};
+use crate::dma::DmaChannel;
+use crate::sync::NdsMutex;
+use crate::{agbabi, math, mmio};
 use bitfield_struct::bitfield;
+use fixed::types::{I20F12, I8F8};
 use core::ptr::{read_volatile, write_volatile};
 
 // https://problemkaputt.de/gbatek.htm#lcdobjoverview
@@ -9,6 +12,65 @@ use core::ptr::{read_volatile, write_volatile};
 const AFFINE_FLAG: u64 = 1 << 8;
 pub const DISABLED_SPRITE: Sprite = Sprite::NormalSprite(NormalSprite::new().with_disable(true));
 
+/// How a sprite is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjMode {
+    Normal = 0,
+    /// Blended with the layers below it, using the alpha blending settings
+    SemiTransparent = 1,
+    /// Not drawn, used as the shape of the OBJ window instead
+    Window = 2,
+    /// Uses a 16 bit direct colour bitmap instead of tiles
+    Bitmap = 3,
+}
+bitfield_enum!(ObjMode: u64 { 0 => Normal, 1 => SemiTransparent, 2 => Window, 3 => Bitmap } else Normal);
+
+/// Shape of a sprite. Used with [`ObjSize`] to get the dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjShape {
+    Square = 0,
+    Horizontal = 1,
+    Vertical = 2,
+}
+bitfield_enum!(ObjShape: u64 { 0 => Square, 1 => Horizontal, 2 => Vertical } else Square);
+
+/// Size of a sprite. The dimensions depend on the [`ObjShape`]:
+///
+/// | Size  | Square | Horizontal | Vertical |
+/// |-------|--------|------------|----------|
+/// | Size0 | 8x8    | 16x8       | 8x16     |
+/// | Size1 | 16x16  | 32x8       | 8x32     |
+/// | Size2 | 32x32  | 32x16      | 16x32    |
+/// | Size3 | 64x64  | 64x32      | 32x64    |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjSize {
+    Size0 = 0,
+    Size1 = 1,
+    Size2 = 2,
+    Size3 = 3,
+}
+bitfield_enum!(ObjSize: u64 { 0 => Size0, 1 => Size1, 2 => Size2, 3 => Size3 } else Size0);
+
+impl ObjSize {
+    /// Gets the width and height in pixels of a sprite with this size and `shape`.
+    #[must_use]
+    pub const fn dimensions(self, shape: ObjShape) -> (u8, u8) {
+        const SQUARE: [u8; 4] = [8, 16, 32, 64];
+        const LONG: [u8; 4] = [16, 32, 32, 64];
+        const SHORT: [u8; 4] = [8, 8, 16, 32];
+        let i = self as usize;
+        match shape {
+            ObjShape::Square => (SQUARE[i], SQUARE[i]),
+            ObjShape::Horizontal => (LONG[i], SHORT[i]),
+            ObjShape::Vertical => (SHORT[i], LONG[i]),
+        }
+    }
+}
+
+// shape 3 is "prohibited"
+const SHAPE_SHIFT: u64 = 14;
+const SHAPE_MASK: u64 = 3 << SHAPE_SHIFT;
+
 #[bitfield(u64)]
 #[derive(PartialEq, Eq)]
 pub struct NormalSprite {
@@ -16,11 +78,12 @@ pub struct NormalSprite {
     _p: bool, // normal sprite indicator (must be 0)
     pub disable: bool,
     #[bits(2)]
-    pub mode: u8, //enum
+    pub mode: ObjMode,
     pub mosaic: bool,
-    pub palette_type: bool, //enum
+    #[bits(1)]
+    pub palette_type: PaletteType,
     #[bits(2)]
-    pub shape: u8, // enum
+    pub shape: ObjShape,
     #[bits(9)]
     pub x: u16,
     #[bits(3)]
@@ -28,7 +91,7 @@ pub struct NormalSprite {
     pub h_flip: bool,
     pub v_flip: bool,
     #[bits(2)]
-    pub size: u8,
+    pub size: ObjSize,
     #[bits(10)]
     pub tile: u16,
     #[bits(2)]
@@ -45,17 +108,18 @@ pub struct AffineSprite {
     _p: bool, // affine sprite indicator (must be 1)
     pub double_size: bool,
     #[bits(2)]
-    pub mode: u8, //enum
+    pub mode: ObjMode,
     pub mosaic: bool,
-    pub palette_type: bool, //enum
+    #[bits(1)]
+    pub palette_type: PaletteType,
     #[bits(2)]
-    pub shape: u8, // enum
+    pub shape: ObjShape,
     #[bits(9)]
     pub x: u16,
     #[bits(5)]
     pub affine_param: u8,
     #[bits(2)]
-    pub size: u8,
+    pub size: ObjSize,
     #[bits(10)]
     pub tile: u16,
     #[bits(2)]
@@ -65,13 +129,93 @@ pub struct AffineSprite {
     _p: u16,
 }
 
+impl NormalSprite {
+    /// Converts raw OAM attributes, returning `None` if the shape is invalid or it's an affine sprite.
+    #[must_use]
+    pub fn try_from_bits(bits: u64) -> Option<Self> {
+        if bits & SHAPE_MASK == SHAPE_MASK || bits & AFFINE_FLAG != 0 {
+            return None;
+        }
+        Some(Self::from(bits))
+    }
+}
+
+impl AffineSprite {
+    /// Converts raw OAM attributes, returning `None` if the shape is invalid or it's not an affine sprite.
+    #[must_use]
+    pub fn try_from_bits(bits: u64) -> Option<Self> {
+        if bits & SHAPE_MASK == SHAPE_MASK || bits & AFFINE_FLAG == 0 {
+            return None;
+        }
+        Some(Self::from(bits))
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct AffineParameter {
-    pub pa: fixed::types::I8F8,
-    pub pb: fixed::types::I8F8,
-    pub pc: fixed::types::I8F8,
-    pub pd: fixed::types::I8F8,
+    pub pa: I8F8,
+    pub pb: I8F8,
+    pub pc: I8F8,
+    pub pd: I8F8,
+}
+
+impl AffineParameter {
+    /// The identity matrix, which draws the sprite or background normally.
+    pub const IDENTITY: Self = Self { pa: I8F8::ONE, pb: I8F8::ZERO, pc: I8F8::ZERO, pd: I8F8::ONE };
+
+    /// Builds a matrix that rotates the image clockwise by `angle` (a full turn is `0x10000`),
+    /// and scales it by `scale_x` and `scale_y` (2.0 = twice as big on screen).
+    #[must_use]
+    pub fn rotate_scale(angle: u16, scale_x: I20F12, scale_y: I20F12) -> Self {
+        // the hardware maps screen pixels to texture pixels, so this is the inverse transform
+        let (sin, cos) = (math::sin(angle), math::cos(angle));
+        Self {
+            pa: I8F8::saturating_from_num(math::div_fixed(cos, scale_x)),
+            pb: I8F8::saturating_from_num(math::div_fixed(sin, scale_x)),
+            pc: I8F8::saturating_from_num(math::div_fixed(-sin, scale_y)),
+            pd: I8F8::saturating_from_num(math::div_fixed(cos, scale_y)),
+        }
+    }
+
+    /// Builds a matrix that rotates the image clockwise by `angle` (a full turn is `0x10000`).
+    #[must_use]
+    #[inline]
+    pub fn rotate(angle: u16) -> Self {
+        Self::rotate_scale(angle, I20F12::ONE, I20F12::ONE)
+    }
+
+    /// Builds a matrix that scales the image by `scale_x` and `scale_y` (2.0 = twice as big on screen).
+    #[must_use]
+    #[inline]
+    pub fn scale(scale_x: I20F12, scale_y: I20F12) -> Self {
+        Self::rotate_scale(0, scale_x, scale_y)
+    }
+
+    /// Builds a matrix that shears the image.
+    ///
+    /// Each screen pixel shows the texture pixel at `(x + shear_x * y, y + shear_y * x)`.
+    #[must_use]
+    #[inline]
+    pub const fn shear(shear_x: I8F8, shear_y: I8F8) -> Self {
+        Self { pa: I8F8::ONE, pb: shear_x, pc: shear_y, pd: I8F8::ONE }
+    }
+
+    /// Combines two transforms, so the result looks like `self` is applied to the image first, then `then`.
+    #[must_use]
+    pub fn then(self, then: Self) -> Self {
+        // both are screen -> texture maps, so the combined map is self * then
+        let mul = |a: I8F8, b: I8F8, c: I8F8, d: I8F8| {
+            let bits = (a.to_bits() as i32 * b.to_bits() as i32 + c.to_bits() as i32 * d.to_bits() as i32) >> 8;
+            I8F8::from_bits(bits.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+        };
+        Self {
+            pa: mul(self.pa, then.pa, self.pb, then.pc),
+            pb: mul(self.pa, then.pb, self.pb, then.pd),
+            pc: mul(self.pc, then.pa, self.pd, then.pc),
+            pd: mul(self.pc, then.pb, self.pd, then.pd),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -84,10 +228,7 @@ pub enum Sprite {
 pub fn set_sprite(engine: GfxEngine, index: u8, sprite: Sprite) {
     let oam_addr = get_oam_addr(engine, index);
 
-    let obj_data = match sprite {
-        Sprite::NormalSprite(s) => u64::from(s) & !AFFINE_FLAG,
-        Sprite::AffineSprite(s) => u64::from(s) | AFFINE_FLAG,
-    };
+    let obj_data = sprite_to_bits(sprite);
     unsafe {
         // Writes OBJ Attributes 0 and 1
         write_volatile(oam_addr as *mut u32, obj_data as u32);
@@ -107,11 +248,7 @@ pub fn get_sprite(engine: GfxEngine, index: u8) -> Sprite {
         // Reads OBJ Attribute 2
         obj_data |= (read_volatile((oam_addr + 4) as *const u16) as u64) << 32;
     }
-    if obj_data & AFFINE_FLAG > 0 {
-        Sprite::AffineSprite(AffineSprite::from(obj_data))
-    } else {
-        Sprite::NormalSprite(NormalSprite::from(obj_data))
-    }
+    sprite_from_bits(obj_data)
 }
 
 #[inline]
@@ -140,6 +277,23 @@ pub fn get_affine_param(engine: GfxEngine, index: u8) -> AffineParameter {
     }
 }
 
+#[inline(always)]
+fn sprite_to_bits(sprite: Sprite) -> u64 {
+    match sprite {
+        Sprite::NormalSprite(s) => u64::from(s) & !AFFINE_FLAG,
+        Sprite::AffineSprite(s) => u64::from(s) | AFFINE_FLAG,
+    }
+}
+
+#[inline(always)]
+fn sprite_from_bits(obj_data: u64) -> Sprite {
+    if obj_data & AFFINE_FLAG > 0 {
+        Sprite::AffineSprite(AffineSprite::from(obj_data))
+    } else {
+        Sprite::NormalSprite(NormalSprite::from(obj_data))
+    }
+}
+
 #[inline(always)]
 const fn get_oam_addr(engine: GfxEngine, index: u8) -> usize {
     debug_assert!(index <= 127, "sprite index must be from 0 to 127");
@@ -163,3 +317,187 @@ const fn get_affine_addr(engine: GfxEngine, index: u8) -> usize {
     let index = index as usize;
     oam_addr + 6 + (index * 32)
 }
+
+// Size of OAM for one engine, in halfwords
+const OAM_LEN: usize = 512;
+// Attribute 0 with the disable bit set
+const DISABLED_ATTR0: u16 = 1 << 9;
+
+/// A copy of one engine's OAM in RAM, which can be edited at any time and copied to OAM all at once.
+///
+/// Editing OAM directly in the middle of a frame can cause tearing. With a buffer, the sprites can be
+/// changed whenever, and then [`commit`](OamBuffer::commit)ted during VBlank.
+///
+/// # Examples
+///
+/// ```
+/// let mut oam = OamBuffer::new(GfxEngine::MAIN);
+/// oam.set_sprite(0, player_sprite)
+///     .set_sprite(1, enemy_sprite)
+///     .hide_sprite(2);
+/// wait_for_vblank();
+/// oam.commit();
+/// ```
+#[repr(C, align(4))]
+#[derive(Clone)]
+pub struct OamBuffer {
+    // same layout as OAM, so it can be copied straight over. Affine parameters are stored in attribute 3.
+    data: [u16; OAM_LEN],
+    engine: GfxEngine,
+}
+
+impl OamBuffer {
+    /// Creates a buffer with all the sprites hidden, and all the affine parameters set to the identity.
+    #[must_use]
+    pub const fn new(engine: GfxEngine) -> Self {
+        let mut data = [0; OAM_LEN];
+        let mut i = 0;
+        while i < 128 {
+            data[i * 4] = DISABLED_ATTR0;
+            i += 1;
+        }
+        let mut i = 0;
+        while i < 32 {
+            data[i * 16 + 3] = I8F8::ONE.to_bits() as u16; // pa
+            data[i * 16 + 15] = I8F8::ONE.to_bits() as u16; // pd
+            i += 1;
+        }
+        Self { data, engine }
+    }
+
+    /// The engine whose OAM this buffer is copied to.
+    #[must_use]
+    #[inline]
+    pub const fn engine(&self) -> GfxEngine {
+        self.engine
+    }
+
+    /// Sets one of the 128 sprites.
+    #[inline]
+    pub fn set_sprite(&mut self, index: u8, sprite: Sprite) -> &mut Self {
+        debug_assert!(index <= 127, "sprite index must be from 0 to 127 (was: {index})");
+        let bits = sprite_to_bits(sprite);
+        let i = (index as usize & 127) * 4;
+        // attribute 3 is part of an affine parameter, so leave it alone
+        self.data[i] = bits as u16;
+        self.data[i + 1] = (bits >> 16) as u16;
+        self.data[i + 2] = (bits >> 32) as u16;
+        self
+    }
+
+    /// Gets one of the 128 sprites.
+    #[must_use]
+    #[inline]
+    pub fn sprite(&self, index: u8) -> Sprite {
+        debug_assert!(index <= 127, "sprite index must be from 0 to 127 (was: {index})");
+        let i = (index as usize & 127) * 4;
+        sprite_from_bits(self.data[i] as u64 | (self.data[i + 1] as u64) << 16 | (self.data[i + 2] as u64) << 32)
+    }
+
+    /// Changes one of the 128 sprites with a function.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// oam.modify_sprite(0, |s| match s {
+    ///     Sprite::NormalSprite(n) => Sprite::NormalSprite(n.with_x(x).with_y(y)),
+    ///     other => other,
+    /// });
+    /// ```
+    #[inline]
+    pub fn modify_sprite(&mut self, index: u8, f: impl FnOnce(Sprite) -> Sprite) -> &mut Self {
+        let sprite = f(self.sprite(index));
+        self.set_sprite(index, sprite)
+    }
+
+    /// Hides one of the 128 sprites, by setting it to [`DISABLED_SPRITE`].
+    #[inline]
+    pub fn hide_sprite(&mut self, index: u8) -> &mut Self {
+        self.set_sprite(index, DISABLED_SPRITE)
+    }
+
+    /// Hides all the sprites. The affine parameters aren't changed.
+    pub fn hide_all(&mut self) -> &mut Self {
+        for index in 0..128 {
+            self.hide_sprite(index);
+        }
+        self
+    }
+
+    /// Sets one of the 32 affine parameters.
+    #[inline]
+    pub fn set_affine_param(&mut self, index: u8, param: AffineParameter) -> &mut Self {
+        debug_assert!(index <= 31, "sprite affine parameter index must be from 0 to 31 (was: {index})");
+        let i = (index as usize & 31) * 16 + 3;
+        self.data[i] = param.pa.to_bits() as u16;
+        self.data[i + 4] = param.pb.to_bits() as u16;
+        self.data[i + 8] = param.pc.to_bits() as u16;
+        self.data[i + 12] = param.pd.to_bits() as u16;
+        self
+    }
+
+    /// Gets one of the 32 affine parameters.
+    #[must_use]
+    #[inline]
+    pub fn affine_param(&self, index: u8) -> AffineParameter {
+        debug_assert!(index <= 31, "sprite affine parameter index must be from 0 to 31 (was: {index})");
+        let i = (index as usize & 31) * 16 + 3;
+        AffineParameter {
+            pa: I8F8::from_bits(self.data[i] as i16),
+            pb: I8F8::from_bits(self.data[i + 4] as i16),
+            pc: I8F8::from_bits(self.data[i + 8] as i16),
+            pd: I8F8::from_bits(self.data[i + 12] as i16),
+        }
+    }
+
+    #[inline(always)]
+    fn oam_base(&self) -> usize {
+        match self.engine {
+            GfxEngine::MAIN => mmio::OAM_BASE_MAIN,
+            GfxEngine::SUB => mmio::OAM_BASE_SUB,
+        }
+    }
+
+    /// Copies the whole buffer into OAM, using the CPU.
+    ///
+    /// To avoid tearing, this should be done during VBlank.
+    pub fn commit(&self) {
+        unsafe { agbabi::__aeabi_memcpy4(self.oam_base() as *mut u8, self.data.as_ptr().cast(), OAM_LEN * 2); }
+    }
+
+    /// Copies the whole buffer into OAM, using a DMA channel. Waits for the copy to finish.
+    ///
+    /// To avoid tearing, this should be done during VBlank.
+    ///
+    /// # Panics
+    /// Panics if the buffer is in DTCM, which DMA can't access. Local variables are in DTCM,
+    /// so put the buffer in a `static` or a `Box` instead.
+    pub fn commit_dma(&self, channel: DmaChannel) {
+        unsafe { channel.copy_raw(self.data.as_ptr().cast(), self.oam_base() as *mut u8, OAM_LEN as u32 / 2, crate::dma::DmaUnit::Bits32); }
+    }
+}
+
+/// Commits each of `buffers` that isn't locked. Call this at the start of your VBlank interrupt handler.
+///
+/// A buffer is skipped for a frame if it's locked when VBlank starts, so only finished changes are shown.
+///
+/// # Examples
+///
+/// ```
+/// static OAM: [NdsMutex<OamBuffer>; 2] = [
+///     NdsMutex::new(OamBuffer::new(GfxEngine::MAIN)),
+///     NdsMutex::new(OamBuffer::new(GfxEngine::SUB)),
+/// ];
+/// irq_set_handler(IRQType::Vblank, || commit_all(&OAM));
+/// irq_enable(IRQFlags::VBLANK);
+/// // then, in the main loop (a locked buffer isn't committed, so keep the lock while making related changes)
+/// let mut oam = OAM[0].lock();
+/// oam.set_sprite(0, body).set_sprite(1, head);
+/// ```
+pub fn commit_all(buffers: &[NdsMutex<OamBuffer>]) {
+    for buffer in buffers {
+        if let Some(b) = buffer.try_lock() {
+            b.commit();
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Hands out OAM sprite and affine parameter slots, so different parts of a program don't fight over them.
+//!
+//! Sprites are edited through their [`SpriteHandle`], then [`ObjAllocator::write_to`] copies all the
+//! sprites in use into an [`OamBuffer`]. Slots are freed when their handle is dropped.
+
+use super::obj::{AffineParameter, OamBuffer, Sprite, DISABLED_SPRITE};
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+const NUM_SPRITES: usize = 128;
+const NUM_AFFINE: usize = 32;
+
+#[derive(Clone, Copy)]
+struct SpriteSlot {
+    sprite: Sprite,
+    depth: i32,
+    used: bool,
+}
+
+struct AllocatorState {
+    sprites: [SpriteSlot; NUM_SPRITES],
+    affine: [AffineParameter; NUM_AFFINE],
+    affine_used: u32,
+    sort_by_depth: bool,
+}
+
+/// Allocator for the sprites and affine parameters of one engine.
+///
+/// The allocator can be cloned, and all the clones share the same slots.
+#[derive(Clone)]
+pub struct ObjAllocator {
+    state: Rc<RefCell<AllocatorState>>,
+}
+
+/// A sprite slot. The slot is freed (and the sprite hidden) when this is dropped.
+pub struct SpriteHandle {
+    state: Rc<RefCell<AllocatorState>>,
+    slot: u8,
+}
+
+/// An affine parameter slot. The slot is freed when this is dropped.
+pub struct AffineHandle {
+    state: Rc<RefCell<AllocatorState>>,
+    index: u8,
+}
+
+impl ObjAllocator {
+    /// Creates an allocator with all the slots free.
+    ///
+    /// If `sort_by_depth` is true, sprites are put into OAM in order of their depth (see [`SpriteHandle::set_depth`]).
+    /// Otherwise, they keep the order of their slots.
+    #[must_use]
+    pub fn new(sort_by_depth: bool) -> Self {
+        let empty = SpriteSlot { sprite: DISABLED_SPRITE, depth: 0, used: false };
+        Self {
+            state: Rc::new(RefCell::new(AllocatorState {
+                sprites: [empty; NUM_SPRITES],
+                affine: [AffineParameter::IDENTITY; NUM_AFFINE],
+                affine_used: 0,
+                sort_by_depth,
+            })),
+        }
+    }
+
+    /// Takes a free sprite slot, or returns `None` if all 128 are in use.
+    ///
+    /// The sprite starts out hidden, with a depth of 0.
+    #[must_use]
+    pub fn alloc_sprite(&self) -> Option<SpriteHandle> {
+        let mut state = self.state.borrow_mut();
+        let slot = state.sprites.iter().position(|s| !s.used)?;
+        state.sprites[slot] = SpriteSlot { sprite: DISABLED_SPRITE, depth: 0, used: true };
+        Some(SpriteHandle { state: self.state.clone(), slot: slot as u8 })
+    }
+
+    /// Takes a free affine parameter slot, or returns `None` if all 32 are in use.
+    ///
+    /// The parameter starts out as the identity matrix.
+    #[must_use]
+    pub fn alloc_affine(&self) -> Option<AffineHandle> {
+        let mut state = self.state.borrow_mut();
+        let index = (!state.affine_used).trailing_zeros();
+        if index as usize >= NUM_AFFINE {
+            return None;
+        }
+        state.affine_used |= 1 << index;
+        state.affine[index as usize] = AffineParameter::IDENTITY;
+        Some(AffineHandle { state: self.state.clone(), index: index as u8 })
+    }
+
+    /// The number of sprite slots in use.
+    #[must_use]
+    pub fn sprite_count(&self) -> usize {
+        self.state.borrow().sprites.iter().filter(|s| s.used).count()
+    }
+
+    /// The number of affine parameter slots in use.
+    #[must_use]
+    pub fn affine_count(&self) -> usize {
+        self.state.borrow().affine_used.count_ones() as usize
+    }
+
+    /// Writes all the sprites and affine parameters into `buffer`.
+    ///
+    /// The sprites in use are packed at the start of OAM (sorted by depth, if enabled),
+    /// and the rest of the entries are set to [`DISABLED_SPRITE`].
+    pub fn write_to(&self, buffer: &mut OamBuffer) {
+        let state = self.state.borrow();
+        let mut order = [0u8; NUM_SPRITES];
+        let mut count = 0;
+        for (i, s) in state.sprites.iter().enumerate() {
+            if s.used {
+                order[count] = i as u8;
+                count += 1;
+            }
+        }
+        if state.sort_by_depth {
+            // ties keep the slot order, so sprites with the same depth don't flicker
+            order[..count].sort_unstable_by_key(|&i| (state.sprites[i as usize].depth, i));
+        }
+        for (oam_index, &slot) in order[..count].iter().enumerate() {
+            buffer.set_sprite(oam_index as u8, state.sprites[slot as usize].sprite);
+        }
+        for oam_index in count..NUM_SPRITES {
+            buffer.hide_sprite(oam_index as u8);
+        }
+        for (i, param) in state.affine.iter().enumerate() {
+            buffer.set_affine_param(i as u8, *param);
+        }
+    }
+}
+
+impl SpriteHandle {
+    /// Sets the sprite shown in this slot.
+    #[inline]
+    pub fn set(&self, sprite: Sprite) {
+        self.state.borrow_mut().sprites[self.slot as usize].sprite = sprite;
+    }
+
+    /// Gets the sprite shown in this slot.
+    #[must_use]
+    #[inline]
+    pub fn get(&self) -> Sprite {
+        self.state.borrow().sprites[self.slot as usize].sprite
+    }
+
+    /// Hides the sprite, without freeing the slot.
+    #[inline]
+    pub fn hide(&self) {
+        self.set(DISABLED_SPRITE);
+    }
+
+    /// Sets the depth used for sorting. Sprites with a lower depth are drawn in front of ones with a higher depth
+    /// (if they have the same priority). Only used if the allocator was created with `sort_by_depth`.
+    #[inline]
+    pub fn set_depth(&self, depth: i32) {
+        self.state.borrow_mut().sprites[self.slot as usize].depth = depth;
+    }
+
+    /// Gets the depth used for sorting.
+    #[must_use]
+    #[inline]
+    pub fn depth(&self) -> i32 {
+        self.state.borrow().sprites[self.slot as usize].depth
+    }
+}
+
+impl Drop for SpriteHandle {
+    fn drop(&mut self) {
+        self.state.borrow_mut().sprites[self.slot as usize] = SpriteSlot { sprite: DISABLED_SPRITE, depth: 0, used: false };
+    }
+}
+
+impl AffineHandle {
+    /// The affine parameter index (0-31), to use as the `affine_param` of an [`AffineSprite`](super::obj::AffineSprite).
+    ///
+    /// Unlike sprites, affine parameters aren't reordered, so this is the real index in OAM.
+    #[must_use]
+    #[inline]
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Sets the affine parameter.
+    #[inline]
+    pub fn set(&self, param: AffineParameter) {
+        self.state.borrow_mut().affine[self.index as usize] = param;
+    }
+
+    /// Gets the affine parameter.
+    #[must_use]
+    #[inline]
+    pub fn get(&self) -> AffineParameter {
+        self.state.borrow().affine[self.index as usize]
+    }
+}
+
+impl Drop for AffineHandle {
+    fn drop(&mut self) {
+        let mut state = self.state.borrow_mut();
+        state.affine_used &= !(1 << self.index);
+        state.affine[self.index as usize] = AffineParameter::IDENTITY;
+    }
+}
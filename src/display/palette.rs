@@ -0,0 +1,382 @@
+//! Module for reading and writing palettes, including extended palettes in VRAM.
+//!
+//! Palettes can also be faded ([`PaletteFade`]) and cycled ([`PaletteCycle`]) over a number of frames,
+//! by calling `step` once per frame.
+
+use super::vram::write_control;
+use super::{rgb15, GfxEngine, VramBank, VramRegion};
+use crate::interrupt::critical_section;
+use crate::mmio;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+// https://problemkaputt.de/gbatek.htm#lcdcolorpalettes
+// https://problemkaputt.de/gbatek.htm#dsvramextendedpalettes
+
+/// Size of one extended palette slot, in colours (16 palettes of 256 colours).
+pub const EXT_PALETTE_SLOT_LEN: usize = 16 * 256;
+
+/// A 15 bit colour, as used by the palettes and direct colour bitmaps.
+///
+/// Bits 0-4 are red, 5-9 are green and 10-14 are blue. Bit 15 is only used by bitmaps, where it means the pixel is shown.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Color(u16);
+
+impl Color {
+    pub const BLACK: Self = Self::from_rgb(0, 0, 0);
+    pub const WHITE: Self = Self::from_rgb(31, 31, 31);
+    pub const RED: Self = Self::from_rgb(31, 0, 0);
+    pub const GREEN: Self = Self::from_rgb(0, 31, 0);
+    pub const BLUE: Self = Self::from_rgb(0, 0, 31);
+
+    /// Makes a colour from 5 bit components (0 to 31).
+    #[must_use]
+    #[inline]
+    pub const fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        Self((r as u16 & 0x1F) | (g as u16 & 0x1F) << 5 | (b as u16 & 0x1F) << 10)
+    }
+
+    /// Makes a colour from a standard hexcode (0xRRGGBB). The bottom 3 bits of each component are lost.
+    #[must_use]
+    #[inline]
+    pub const fn from_hex(x: u32) -> Self {
+        Self(rgb15(x))
+    }
+
+    /// Makes a colour from its raw value.
+    #[must_use]
+    #[inline]
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    /// Gets the raw value of the colour.
+    #[must_use]
+    #[inline]
+    pub const fn to_bits(self) -> u16 {
+        self.0
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn r(self) -> u8 {
+        (self.0 & 0x1F) as u8
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn g(self) -> u8 {
+        ((self.0 >> 5) & 0x1F) as u8
+    }
+
+    #[must_use]
+    #[inline]
+    pub const fn b(self) -> u8 {
+        ((self.0 >> 10) & 0x1F) as u8
+    }
+
+    /// Sets bit 15, which makes a pixel of a direct colour bitmap visible.
+    #[must_use]
+    #[inline]
+    pub const fn opaque(self) -> Self {
+        Self(self.0 | 0x8000)
+    }
+
+    /// Mixes this colour with `other`. At `num / den` = 0 it's this colour, and at 1 it's `other`.
+    #[must_use]
+    pub const fn lerp(self, other: Self, num: u32, den: u32) -> Self {
+        debug_assert!(den != 0 && num <= den, "lerp amount must be from 0 to 1");
+        const fn mix(a: u8, b: u8, num: u32, den: u32) -> u8 {
+            (a as i32 + (b as i32 - a as i32) * num as i32 / den as i32) as u8
+        }
+        Self::from_rgb(
+            mix(self.r(), other.r(), num, den),
+            mix(self.g(), other.g(), num, den),
+            mix(self.b(), other.b(), num, den),
+        )
+    }
+}
+
+impl From<u16> for Color {
+    fn from(bits: u16) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<Color> for u16 {
+    fn from(color: Color) -> Self {
+        color.0
+    }
+}
+
+/// A block of palette memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    addr: usize,
+    len: usize,
+}
+
+impl Palette {
+    /// The 256 colour background palette of an engine. Colour 0 is the backdrop.
+    #[must_use]
+    #[inline]
+    pub const fn bg(engine: GfxEngine) -> Self {
+        let addr = match engine {
+            GfxEngine::MAIN => mmio::BG_PALETTE_RAM_BASE_MAIN,
+            GfxEngine::SUB => mmio::BG_PALETTE_RAM_BASE_SUB,
+        };
+        Self { addr, len: 256 }
+    }
+
+    /// The 256 colour sprite palette of an engine.
+    #[must_use]
+    #[inline]
+    pub const fn obj(engine: GfxEngine) -> Self {
+        let addr = match engine {
+            GfxEngine::MAIN => mmio::OBJ_PALETTE_RAM_BASE_MAIN,
+            GfxEngine::SUB => mmio::OBJ_PALETTE_RAM_BASE_SUB,
+        };
+        Self { addr, len: 256 }
+    }
+
+    /// The number of colours in the palette.
+    #[must_use]
+    #[inline]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if the palette has no colours.
+    #[must_use]
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets one of the 16 colour banks of this palette (0 to 15), used by 16 colour tiles and sprites.
+    #[must_use]
+    #[inline]
+    pub fn bank16(&self, bank: usize) -> Self {
+        self.sub_palette(bank, 16)
+    }
+
+    /// Gets one of the 256 colour palettes of an extended palette slot (0 to 15).
+    #[must_use]
+    #[inline]
+    pub fn bank256(&self, bank: usize) -> Self {
+        self.sub_palette(bank, 256)
+    }
+
+    fn sub_palette(&self, bank: usize, len: usize) -> Self {
+        assert!((bank + 1) * len <= self.len, "palette bank {bank} is out of range");
+        Self { addr: self.addr + bank * len * 2, len }
+    }
+
+    #[inline(always)]
+    fn ptr(&self, index: usize) -> *mut u16 {
+        assert!(index < self.len, "palette index {index} is out of range (len: {})", self.len);
+        (self.addr + index * 2) as *mut u16
+    }
+
+    /// Sets one colour.
+    #[inline]
+    pub fn set(&self, index: usize, color: Color) {
+        unsafe { write_volatile(self.ptr(index), color.0); }
+    }
+
+    /// Gets one colour.
+    #[must_use]
+    #[inline]
+    pub fn get(&self, index: usize) -> Color {
+        unsafe { Color(read_volatile(self.ptr(index))) }
+    }
+
+    /// Sets the colours starting at `start`.
+    pub fn write(&self, start: usize, colors: &[Color]) {
+        assert!(start + colors.len() <= self.len, "colours don't fit in the palette");
+        for (i, &color) in colors.iter().enumerate() {
+            self.set(start + i, color);
+        }
+    }
+
+    /// Reads the colours starting at `start` into `colors`.
+    pub fn read(&self, start: usize, colors: &mut [Color]) {
+        assert!(start + colors.len() <= self.len, "colours don't fit in the palette");
+        for (i, color) in colors.iter_mut().enumerate() {
+            *color = self.get(start + i);
+        }
+    }
+
+    /// Sets every colour of the palette to `color`.
+    pub fn fill(&self, color: Color) {
+        for i in 0..self.len {
+            self.set(i, color);
+        }
+    }
+}
+
+/// The extended palettes stored in a VRAM bank. See [`edit_ext_palettes`].
+#[derive(Debug, Clone, Copy)]
+pub struct ExtPalettes {
+    region: VramRegion,
+    // first slot in this bank, and the LCDC address where it can be accessed
+    first_slot: usize,
+    slots: usize,
+    addr: usize,
+}
+
+impl ExtPalettes {
+    /// Gets an extended palette slot, if it's stored in this bank.
+    ///
+    /// BG slots 0 to 3 are used by BG0 to BG3 (BG0 and BG1 can also use slots 2 and 3, with `bit13` in
+    /// [`BackgroundControl`](super::BackgroundControl)). OBJ extended palettes only have slot 0.
+    /// Each slot has 16 palettes of 256 colours, which can be picked with [`Palette::bank256`].
+    #[must_use]
+    pub fn slot(&self, slot: usize) -> Option<Palette> {
+        if slot < self.first_slot || slot >= self.first_slot + self.slots {
+            return None;
+        }
+        Some(Palette {
+            addr: self.addr + (slot - self.first_slot) * EXT_PALETTE_SLOT_LEN * 2,
+            len: EXT_PALETTE_SLOT_LEN,
+        })
+    }
+
+    /// Where the bank is mapped (one of the extended palette regions).
+    #[must_use]
+    #[inline]
+    pub fn region(&self) -> VramRegion {
+        self.region
+    }
+}
+
+/// Lets `f` edit the extended palettes in `bank`.
+///
+/// Extended palettes can't be accessed by the CPU, so the bank is mapped to LCDC while `f` runs,
+/// then mapped back. The extended palettes aren't shown while this happens, so it should be done
+/// during VBlank, or while the layers using them are hidden.
+///
+/// # Panics
+/// Panics if `bank` isn't mapped to an extended palette region with [`map_vram`](super::map_vram).
+pub fn edit_ext_palettes<R>(bank: VramBank, f: impl FnOnce(&ExtPalettes) -> R) -> R {
+    let control = bank.control();
+    let mapping = bank.mapping().expect("bank must be mapped to an extended palette");
+    let slot_bytes = match mapping.region {
+        VramRegion::MainBgExtPalette | VramRegion::MainObjExtPalette
+        | VramRegion::SubBgExtPalette | VramRegion::SubObjExtPalette => EXT_PALETTE_SLOT_LEN * 2,
+        _ => panic!("bank must be mapped to an extended palette"),
+    };
+    let palettes = ExtPalettes {
+        region: mapping.region,
+        first_slot: mapping.start / slot_bytes,
+        slots: mapping.len / slot_bytes,
+        addr: bank.lcdc_addr(),
+    };
+    // mapping to LCDC only changes where the bank is, so it can't overlap with anything
+    critical_section!({ write_control(bank, 0x80); });
+    let result = f(&palettes);
+    critical_section!({ write_control(bank, control); });
+    result
+}
+
+/// Fades part of a palette between two sets of colours.
+///
+/// Call [`step`](Self::step) once per frame.
+pub struct PaletteFade {
+    palette: Palette,
+    start: usize,
+    from: Vec<Color>,
+    to: Vec<Color>,
+    frames: u32,
+    frame: u32,
+}
+
+impl PaletteFade {
+    /// Fades the colours starting at `start` from what they are now to `to`, over `frames` frames.
+    #[must_use]
+    pub fn new(palette: Palette, start: usize, to: &[Color], frames: u32) -> Self {
+        let mut from = alloc::vec![Color::BLACK; to.len()];
+        palette.read(start, &mut from);
+        Self { palette, start, from, to: to.into(), frames: frames.max(1), frame: 0 }
+    }
+
+    /// Fades `len` colours starting at `start` from what they are now to a single colour
+    /// (for example, [`Color::BLACK`] to fade out).
+    #[must_use]
+    pub fn to_color(palette: Palette, start: usize, len: usize, color: Color, frames: u32) -> Self {
+        Self::new(palette, start, &alloc::vec![color; len], frames)
+    }
+
+    /// Fades the other way, from the end colours back to the start colours.
+    #[must_use]
+    pub fn reversed(self) -> Self {
+        Self { from: self.to, to: self.from, frame: 0, ..self }
+    }
+
+    /// Moves the fade forward one frame and writes the colours.
+    ///
+    /// Returns `true` once the fade is finished.
+    pub fn step(&mut self) -> bool {
+        if self.frame < self.frames {
+            self.frame += 1;
+            for (i, (&from, &to)) in self.from.iter().zip(self.to.iter()).enumerate() {
+                self.palette.set(self.start + i, from.lerp(to, self.frame, self.frames));
+            }
+        }
+        self.is_finished()
+    }
+
+    /// Checks if the fade has reached the end colours.
+    #[must_use]
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.frames
+    }
+}
+
+/// Rotates part of a palette, for animations like flowing water.
+///
+/// Call [`step`](Self::step) once per frame.
+pub struct PaletteCycle {
+    palette: Palette,
+    start: usize,
+    len: usize,
+    frames_per_shift: u32,
+    counter: u32,
+    backwards: bool,
+}
+
+impl PaletteCycle {
+    /// Rotates the `len` colours starting at `start` by one place every `frames_per_shift` frames.
+    ///
+    /// Colours move up the palette (colour `start` moves to `start + 1`) unless `backwards` is set.
+    #[must_use]
+    pub fn new(palette: Palette, start: usize, len: usize, frames_per_shift: u32, backwards: bool) -> Self {
+        assert!(start + len <= palette.len(), "cycled colours don't fit in the palette");
+        Self { palette, start, len, frames_per_shift: frames_per_shift.max(1), counter: 0, backwards }
+    }
+
+    /// Moves the cycle forward one frame, rotating the colours if it's time to.
+    pub fn step(&mut self) {
+        self.counter += 1;
+        if self.counter < self.frames_per_shift || self.len < 2 {
+            return;
+        }
+        self.counter = 0;
+        let (first, last) = (self.start, self.start + self.len - 1);
+        if self.backwards {
+            let wrapped = self.palette.get(first);
+            for i in first..last {
+                self.palette.set(i, self.palette.get(i + 1));
+            }
+            self.palette.set(last, wrapped);
+        } else {
+            let wrapped = self.palette.get(last);
+            for i in (first..last).rev() {
+                self.palette.set(i + 1, self.palette.get(i));
+            }
+            self.palette.set(first, wrapped);
+        }
+    }
+}
@@ -1,4 +1,7 @@
+use crate::interrupt::critical_section;
 use crate::mmio;
+use crate::sync::NdsCell;
+use core::ops::Range;
 
 // https://www.problemkaputt.de/gbatek.htm#dsmemorycontrolvram
 pub mod vram_type {
@@ -6,6 +9,7 @@ pub mod vram_type {
     const VRAM_ENABLE: u8 = 1 << 7;
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum A { // 128k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0 = 1 | (0 << 3) | VRAM_ENABLE,
@@ -21,6 +25,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum B { // 128k (same options as A)
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0 = 1 | (0 << 3) | VRAM_ENABLE,
@@ -36,6 +41,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum C { // 128k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0 = 1 | (0 << 3) | VRAM_ENABLE,
@@ -52,6 +58,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum D { // 128k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0 = 1 | (0 << 3) | VRAM_ENABLE,
@@ -68,6 +75,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum E { // 64k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0 = 1 | (0 << 3) | VRAM_ENABLE, // only occupies first half of slot 0
@@ -77,6 +85,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum F { // 16k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0_0 = 1 | (0 << 3) | VRAM_ENABLE, // 0x06000000
@@ -97,6 +106,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum G { // 16k (same options as F)
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         MAIN_BG_0_0 = 1 | (0 << 3) | VRAM_ENABLE, // 0x06000000
@@ -117,6 +127,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum H { // 32k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         SUB_BG = 1 | (0 << 3) | VRAM_ENABLE,
@@ -124,6 +135,7 @@ pub mod vram_type {
     }
 
     #[repr(u8)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum I { // 16k
         LCDC = 0 | (0 << 3) | VRAM_ENABLE,
         SUB_BG = 1 | (0 << 3) | VRAM_ENABLE,
@@ -132,58 +144,320 @@ pub mod vram_type {
     }
 }
 
-// todo: deduplicate these functions somehow. proc macro?
-// are they really necessary? could just expose voladdresses of type vram_type::t
+/// One of the 9 VRAM banks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VramBank {
+    A = 0,
+    B = 1,
+    C = 2,
+    D = 3,
+    E = 4,
+    F = 5,
+    G = 6,
+    H = 7,
+    I = 8,
+}
+
+/// Where a VRAM bank can be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VramRegion {
+    /// Plain memory for the CPU, at the bank's fixed address in 0x06800000-0x068A3FFF
+    Lcdc,
+    MainBg,
+    MainObj,
+    SubBg,
+    SubObj,
+    /// ARM7 work RAM, at 0x06000000-0x0603FFFF on the ARM7 side
+    Arm7,
+    /// 3D texture slots (128K each), not visible to the CPU
+    Texture,
+    /// 3D texture palette slots (16K each), not visible to the CPU
+    TexturePalette,
+    /// Main engine BG extended palette slots (8K each), not visible to the CPU
+    MainBgExtPalette,
+    /// Main engine OBJ extended palette (8K), not visible to the CPU
+    MainObjExtPalette,
+    /// Sub engine BG extended palette slots (8K each), not visible to the CPU
+    SubBgExtPalette,
+    /// Sub engine OBJ extended palette (8K), not visible to the CPU
+    SubObjExtPalette,
+}
+
+/// Where a VRAM bank is mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VramMapping {
+    pub bank: VramBank,
+    pub region: VramRegion,
+    /// Start of the mapping. For regions the ARM9 can see, this is the address.
+    /// Otherwise, it's the byte offset into the region (e.g. texture slot 1 starts at 0x20000).
+    pub start: usize,
+    /// Size of the mapping in bytes. Can be smaller than the bank, if the region doesn't use all of it.
+    pub len: usize,
+}
+
+/// Error returned when a VRAM bank can't be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VramError {
+    /// The mapping would overlap with the mapping of another bank.
+    Overlap(VramMapping),
+}
+
+/// A setting for the VRAMCNT register of a bank. Implemented by all the types in [`vram_type`].
+pub trait VramType: Copy {
+    /// The bank this setting is for.
+    const BANK: VramBank;
+    /// The raw value written to VRAMCNT.
+    fn bits(self) -> u8;
+}
+
+macro impl_vram_type($($t:ident),+) {
+    $(impl VramType for vram_type::$t {
+        const BANK: VramBank = VramBank::$t;
+        #[inline(always)]
+        fn bits(self) -> u8 {
+            self as u8
+        }
+    })+
+}
+impl_vram_type!(A, B, C, D, E, F, G, H, I);
+
+const VRAM_ENABLE: u8 = 1 << 7;
+
+// Shadow copies of the VRAMCNT registers, which can't be read on the ARM9
+static BANK_CONTROL: [NdsCell<u8>; 9] = [const { NdsCell::new(0) }; 9];
+
+impl VramBank {
+    /// All the banks, in order.
+    pub const ALL: [Self; 9] = [Self::A, Self::B, Self::C, Self::D, Self::E, Self::F, Self::G, Self::H, Self::I];
+
+    /// Size of the bank in bytes.
+    #[must_use]
+    pub const fn size(self) -> usize {
+        match self {
+            Self::A | Self::B | Self::C | Self::D => 128 * 1024,
+            Self::E => 64 * 1024,
+            Self::F | Self::G | Self::I => 16 * 1024,
+            Self::H => 32 * 1024,
+        }
+    }
+
+    /// Address of the bank when it's mapped to [`VramRegion::Lcdc`].
+    #[must_use]
+    pub const fn lcdc_addr(self) -> usize {
+        match self {
+            Self::A => 0x0680_0000,
+            Self::B => 0x0682_0000,
+            Self::C => 0x0684_0000,
+            Self::D => 0x0686_0000,
+            Self::E => 0x0688_0000,
+            Self::F => 0x0689_0000,
+            Self::G => 0x0689_4000,
+            Self::H => 0x0689_8000,
+            Self::I => 0x068A_0000,
+        }
+    }
+
+    /// Works out where a raw VRAMCNT value maps this bank, or `None` if the value disables the bank
+    /// or isn't valid for it.
+    #[must_use]
+    pub const fn decode(self, control: u8) -> Option<VramMapping> {
+        // https://problemkaputt.de/gbatek.htm#dsmemorycontrolvram
+        if control & VRAM_ENABLE == 0 {
+            return None;
+        }
+        let mst = control & 7;
+        let ofs = ((control >> 3) & 3) as usize;
+        let (region, start, len) = match (self, mst) {
+            (_, 0) => (VramRegion::Lcdc, self.lcdc_addr(), self.size()),
+            (Self::A | Self::B | Self::C | Self::D, 1) => (VramRegion::MainBg, 0x0600_0000 + ofs * 0x2_0000, self.size()),
+            (Self::A | Self::B, 2) if ofs < 2 => (VramRegion::MainObj, 0x0640_0000 + ofs * 0x2_0000, self.size()),
+            (Self::C | Self::D, 2) if ofs < 2 => (VramRegion::Arm7, 0x0600_0000 + ofs * 0x2_0000, self.size()),
+            (Self::A | Self::B | Self::C | Self::D, 3) => (VramRegion::Texture, ofs * 0x2_0000, self.size()),
+            (Self::C, 4) => (VramRegion::SubBg, 0x0620_0000, self.size()),
+            (Self::D, 4) => (VramRegion::SubObj, 0x0660_0000, self.size()),
+            (Self::E, 1) => (VramRegion::MainBg, 0x0600_0000, self.size()),
+            (Self::E, 2) => (VramRegion::MainObj, 0x0640_0000, self.size()),
+            (Self::E, 3) => (VramRegion::TexturePalette, 0, self.size()),
+            // only the first 32K is used
+            (Self::E, 4) => (VramRegion::MainBgExtPalette, 0, 0x8000),
+            (Self::F | Self::G, 1) => (VramRegion::MainBg, 0x0600_0000 + (ofs & 1) * 0x4000 + (ofs >> 1) * 0x1_0000, self.size()),
+            (Self::F | Self::G, 2) => (VramRegion::MainObj, 0x0640_0000 + (ofs & 1) * 0x4000 + (ofs >> 1) * 0x1_0000, self.size()),
+            (Self::F | Self::G, 3) => (VramRegion::TexturePalette, ((ofs & 1) + (ofs >> 1) * 4) * 0x4000, self.size()),
+            (Self::F | Self::G, 4) if ofs < 2 => (VramRegion::MainBgExtPalette, ofs * 0x4000, self.size()),
+            // only the first 8K is used
+            (Self::F | Self::G, 5) => (VramRegion::MainObjExtPalette, 0, 0x2000),
+            (Self::H, 1) => (VramRegion::SubBg, 0x0620_0000, self.size()),
+            (Self::H, 2) => (VramRegion::SubBgExtPalette, 0, self.size()),
+            (Self::I, 1) => (VramRegion::SubBg, 0x0620_8000, self.size()),
+            (Self::I, 2) => (VramRegion::SubObj, 0x0660_0000, self.size()),
+            (Self::I, 3) => (VramRegion::SubObjExtPalette, 0, 0x2000),
+            _ => return None,
+        };
+        Some(VramMapping { bank: self, region, start, len })
+    }
+
+    /// Gets the raw value of this bank's VRAMCNT register.
+    ///
+    /// VRAMCNT can't be read on the ARM9, so this is the last value written with [`map_vram`] (or 0 if none was).
+    /// A bank mapped by the loader before the program started isn't known about, so this is 0 for it.
+    #[must_use]
+    #[inline]
+    pub fn control(self) -> u8 {
+        BANK_CONTROL[self as usize].read()
+    }
+
+    /// Gets where this bank is currently mapped, or `None` if it's disabled.
+    #[must_use]
+    #[inline]
+    pub fn mapping(self) -> Option<VramMapping> {
+        self.decode(self.control())
+    }
+
+    /// Disables the bank, so it isn't mapped anywhere.
+    #[cfg(feature = "arm9")]
+    #[inline]
+    pub fn unmap(self) {
+        write_control(self, 0);
+    }
+}
+
+impl VramMapping {
+    /// The range of addresses the ARM9 can use to access the bank, or `None` if the region isn't visible to it.
+    #[must_use]
+    pub fn cpu_range(&self) -> Option<Range<usize>> {
+        match self.region {
+            VramRegion::Lcdc | VramRegion::MainBg | VramRegion::MainObj | VramRegion::SubBg | VramRegion::SubObj => {
+                Some(self.start..self.start + self.len)
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks if this mapping uses some of the same memory as `other`.
+    #[must_use]
+    pub fn overlaps(&self, other: &VramMapping) -> bool {
+        self.region == other.region && self.start < other.start + other.len && other.start < self.start + self.len
+    }
+}
+
+#[cfg(feature = "arm9")]
+#[inline(always)]
+pub(crate) fn write_control(bank: VramBank, control: u8) {
+    BANK_CONTROL[bank as usize].write(control);
+    match bank {
+        VramBank::A => mmio::VRAMCNT_A.write(control),
+        VramBank::B => mmio::VRAMCNT_B.write(control),
+        VramBank::C => mmio::VRAMCNT_C.write(control),
+        VramBank::D => mmio::VRAMCNT_D.write(control),
+        VramBank::E => mmio::VRAMCNT_E.write(control),
+        VramBank::F => mmio::VRAMCNT_F.write(control),
+        VramBank::G => mmio::VRAMCNT_G.write(control),
+        VramBank::H => mmio::VRAMCNT_H.write(control),
+        VramBank::I => mmio::VRAMCNT_I.write(control),
+    }
+}
+
+/// Maps a VRAM bank, and returns where it was mapped.
+///
+/// The bank is picked by the type of `vtype`, for example `map_vram(vram_type::H::SUB_BG)` maps bank H.
+/// If the mapping would overlap with another bank's mapping (as recorded by this function), nothing is changed
+/// and the other bank's mapping is returned in the error. Remapping a bank somewhere else is always fine.
+/// Banks that were left mapped by the loader aren't recorded, so they aren't included in the overlap check.
+///
+/// # Examples
+///
+/// ```
+/// let mapping = map_vram(vram_type::A::MAIN_BG_0)?;
+/// let bg_vram = mapping.cpu_range().unwrap(); // 0x06000000..0x06020000
+/// ```
+#[cfg(feature = "arm9")]
+pub fn map_vram<T: VramType>(vtype: T) -> Result<VramMapping, VramError> {
+    let bank = T::BANK;
+    let mapping = bank.decode(vtype.bits()).expect("every vram_type value is a valid mapping");
+    let mut result = Ok(mapping);
+    critical_section!({
+        let conflict = VramBank::ALL.iter()
+            .filter(|&&other| other != bank)
+            .filter_map(|other| other.mapping())
+            .find(|other| mapping.overlaps(other));
+        match conflict {
+            Some(other) => result = Err(VramError::Overlap(other)),
+            None => write_control(bank, vtype.bits()),
+        }
+    });
+    result
+}
+
+/// Maps a VRAM bank without checking for overlaps, and returns where it was mapped.
+///
+/// If two banks are mapped to the same memory, reads return both of them ORed together,
+/// and writes go to both.
+#[cfg(feature = "arm9")]
+#[inline]
+pub fn map_vram_unchecked<T: VramType>(vtype: T) -> VramMapping {
+    write_control(T::BANK, vtype.bits());
+    T::BANK.decode(vtype.bits()).expect("every vram_type value is a valid mapping")
+}
+
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_a(vtype: vram_type::A) {
-    mmio::VRAMCNT_A.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_b(vtype: vram_type::B) {
-    mmio::VRAMCNT_B.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_c(vtype: vram_type::C) {
-    mmio::VRAMCNT_C.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_d(vtype: vram_type::D) {
-    mmio::VRAMCNT_D.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_e(vtype: vram_type::E) {
-    mmio::VRAMCNT_E.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_f(vtype: vram_type::F) {
-    mmio::VRAMCNT_F.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_g(vtype: vram_type::G) {
-    mmio::VRAMCNT_G.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_h(vtype: vram_type::H) {
-    mmio::VRAMCNT_H.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
 
 #[cfg(feature = "arm9")]
+#[deprecated(note = "use `map_vram` or `map_vram_unchecked`")]
 #[inline(always)]
 pub fn map_vram_block_i(vtype: vram_type::I) {
-    mmio::VRAMCNT_I.write(vtype as u8);
+    map_vram_unchecked(vtype);
 }
@@ -0,0 +1,324 @@
+//! Hands out background and sprite graphics memory, so tile and map offsets don't have to be worked out by hand.
+//!
+//! The allocators only manage memory, the VRAM banks still have to be mapped with [`map_vram`](super::map_vram).
+//! Like [`obj_alloc`](super::obj_alloc), memory is freed when its handle is dropped.
+
+use super::obj::{ObjShape, ObjSize};
+use super::{GfxEngine, PaletteType, ScreenSize};
+use crate::mmio;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+// https://problemkaputt.de/gbatek.htm#lcdvrambgscreendataformatbgmap
+// https://problemkaputt.de/gbatek.htm#lcdobjvramcharactertiledata
+
+// Screen blocks (tilemap_base) are 2K, and char blocks (tiledata_base) are 16K
+const SCREEN_BLOCK_SIZE: usize = 2 * 1024;
+const CHAR_BLOCK_SIZE: usize = 16 * 1024;
+const SCREEN_BLOCKS_PER_CHAR_BLOCK: usize = CHAR_BLOCK_SIZE / SCREEN_BLOCK_SIZE;
+const MAX_TILEDATA_BASE: usize = 15;
+const MAX_TILEMAP_BASE: usize = 31;
+const MAX_OBJ_TILE: usize = 1023;
+const MAX_UNITS: usize = 1024;
+
+/// Tracks which units of some memory are in use.
+struct Units {
+    used: [u32; MAX_UNITS / 32],
+    count: usize,
+}
+
+impl Units {
+    fn new(count: usize) -> Self {
+        Self { used: [0; MAX_UNITS / 32], count: count.min(MAX_UNITS) }
+    }
+
+    #[inline]
+    fn is_used(&self, unit: usize) -> bool {
+        self.used[unit / 32] & (1 << (unit % 32)) != 0
+    }
+
+    fn set(&mut self, start: usize, len: usize, used: bool) {
+        for unit in start..start + len {
+            if used {
+                self.used[unit / 32] |= 1 << (unit % 32);
+            } else {
+                self.used[unit / 32] &= !(1 << (unit % 32));
+            }
+        }
+    }
+
+    fn is_free(&self, start: usize, len: usize) -> bool {
+        start + len <= self.count && !(start..start + len).any(|unit| self.is_used(unit))
+    }
+
+    /// Finds `len` free units, starting at a multiple of `align` that's at most `max_start`.
+    /// Searches from the top instead of the bottom if `from_top` is set.
+    fn find(&self, len: usize, align: usize, max_start: usize, from_top: bool) -> Option<usize> {
+        if len == 0 || len > self.count {
+            return None;
+        }
+        let last = max_start.min(self.count - len) / align;
+        let mut starts = (0..=last).map(|i| i * align);
+        if from_top {
+            starts.rev().find(|&start| self.is_free(start, len))
+        } else {
+            starts.find(|&start| self.is_free(start, len))
+        }
+    }
+
+    fn free_count(&self) -> usize {
+        (0..self.count).filter(|&unit| !self.is_used(unit)).count()
+    }
+}
+
+/// Allocator for the background memory of one engine.
+///
+/// The memory is split into 2K screen blocks. Tile data starts at a char block (16K, see `tiledata_base`)
+/// and is allocated from the bottom, while maps start at a screen block (see `tilemap_base`) and are
+/// allocated from the top of the first 64K, so the two don't get in each other's way.
+/// Assumes `master_tiledata_base` and `master_tilemap_base` are 0.
+///
+/// The allocator can be cloned, and all the clones share the same memory.
+#[derive(Clone)]
+pub struct BgVramAllocator {
+    units: Rc<RefCell<Units>>,
+    base: usize,
+}
+
+/// Background tile data. The memory is freed when this is dropped.
+pub struct BgTiles {
+    units: Rc<RefCell<Units>>,
+    addr: usize,
+    start: usize,
+    len: usize,
+}
+
+/// A background tilemap. The memory is freed when this is dropped.
+pub struct BgMap {
+    units: Rc<RefCell<Units>>,
+    addr: usize,
+    start: usize,
+    len: usize,
+}
+
+impl BgVramAllocator {
+    /// Creates an allocator for the first `size` bytes of the engine's background memory.
+    ///
+    /// `size` should be the total size of the banks mapped there, starting from the beginning
+    /// (so 32K for the sub engine, if only bank H is mapped).
+    #[must_use]
+    pub fn new(engine: GfxEngine, size: usize) -> Self {
+        let (base, max_size) = match engine {
+            GfxEngine::MAIN => (mmio::BG_RAM_BASE_MAIN, 512 * 1024),
+            GfxEngine::SUB => (mmio::BG_RAM_BASE_SUB, 128 * 1024),
+        };
+        debug_assert!(size <= max_size, "background memory size is too big (was: {size})");
+        Self {
+            units: Rc::new(RefCell::new(Units::new(size.min(max_size) / SCREEN_BLOCK_SIZE))),
+            base,
+        }
+    }
+
+    /// Takes `bytes` of memory for tile data, or returns `None` if there isn't a free space.
+    #[must_use]
+    pub fn alloc_tiles(&self, bytes: usize) -> Option<BgTiles> {
+        let len = bytes.div_ceil(SCREEN_BLOCK_SIZE);
+        let mut units = self.units.borrow_mut();
+        let start = units.find(len, SCREEN_BLOCKS_PER_CHAR_BLOCK, MAX_TILEDATA_BASE * SCREEN_BLOCKS_PER_CHAR_BLOCK, false)?;
+        units.set(start, len, true);
+        Some(BgTiles { units: self.units.clone(), addr: self.base + start * SCREEN_BLOCK_SIZE, start, len })
+    }
+
+    /// Takes `bytes` of memory for a tilemap, or returns `None` if there isn't a free space.
+    #[must_use]
+    pub fn alloc_map(&self, bytes: usize) -> Option<BgMap> {
+        let len = bytes.div_ceil(SCREEN_BLOCK_SIZE);
+        let mut units = self.units.borrow_mut();
+        let start = units.find(len, 1, MAX_TILEMAP_BASE, true)?;
+        units.set(start, len, true);
+        Some(BgMap { units: self.units.clone(), addr: self.base + start * SCREEN_BLOCK_SIZE, start, len })
+    }
+
+    /// Takes the memory for the tilemap of a text background with size `size`.
+    #[must_use]
+    #[inline]
+    pub fn alloc_text_map(&self, size: ScreenSize) -> Option<BgMap> {
+        // 32x32 tiles, 2 bytes each
+        let blocks = match size {
+            ScreenSize::Size0 => 1,
+            ScreenSize::Size1 | ScreenSize::Size2 => 2,
+            ScreenSize::Size3 => 4,
+        };
+        self.alloc_map(blocks * SCREEN_BLOCK_SIZE)
+    }
+
+    /// The number of free bytes. They might not all be next to each other.
+    #[must_use]
+    pub fn free_bytes(&self) -> usize {
+        self.units.borrow().free_count() * SCREEN_BLOCK_SIZE
+    }
+}
+
+impl BgTiles {
+    /// The value to use for `tiledata_base` in [`BackgroundControl`](super::BackgroundControl).
+    #[must_use]
+    #[inline]
+    pub fn tiledata_base(&self) -> u8 {
+        (self.start / SCREEN_BLOCKS_PER_CHAR_BLOCK) as u8
+    }
+
+    /// Address of the start of the tile data.
+    #[must_use]
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Size of the memory in bytes (rounded up to a multiple of 2K).
+    #[must_use]
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.len * SCREEN_BLOCK_SIZE
+    }
+}
+
+impl Drop for BgTiles {
+    fn drop(&mut self) {
+        self.units.borrow_mut().set(self.start, self.len, false);
+    }
+}
+
+impl BgMap {
+    /// The value to use for `tilemap_base` in [`BackgroundControl`](super::BackgroundControl).
+    #[must_use]
+    #[inline]
+    pub fn tilemap_base(&self) -> u8 {
+        self.start as u8
+    }
+
+    /// Address of the start of the tilemap.
+    #[must_use]
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Size of the memory in bytes (rounded up to a multiple of 2K).
+    #[must_use]
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.len * SCREEN_BLOCK_SIZE
+    }
+}
+
+impl Drop for BgMap {
+    fn drop(&mut self) {
+        self.units.borrow_mut().set(self.start, self.len, false);
+    }
+}
+
+/// Allocator for the sprite tile memory of one engine, when using 1D tile mapping.
+///
+/// In 1D mapping, a sprite's `tile` is counted in steps of `32 << tile_obj_1d_bound` bytes,
+/// so memory is handed out in steps of that size.
+///
+/// The allocator can be cloned, and all the clones share the same memory.
+#[derive(Clone)]
+pub struct ObjTileAllocator {
+    units: Rc<RefCell<Units>>,
+    base: usize,
+    bound: u8,
+}
+
+/// Sprite tile data. The memory is freed when this is dropped.
+pub struct ObjTiles {
+    units: Rc<RefCell<Units>>,
+    addr: usize,
+    start: usize,
+    len: usize,
+    bound: u8,
+}
+
+impl ObjTileAllocator {
+    /// Creates an allocator for the first `size` bytes of the engine's sprite memory.
+    ///
+    /// `tile_obj_1d_bound` must match the one set in the engine's display control (0 to 3).
+    /// Only the first 1024 steps can be used, since that's the highest sprite `tile` number.
+    #[must_use]
+    pub fn new(engine: GfxEngine, size: usize, tile_obj_1d_bound: u8) -> Self {
+        debug_assert!(tile_obj_1d_bound <= 3, "tile_obj_1d_bound must be from 0 to 3 (was: {tile_obj_1d_bound})");
+        let bound = tile_obj_1d_bound & 3;
+        let base = match engine {
+            GfxEngine::MAIN => mmio::OBJ_RAM_BASE_MAIN,
+            GfxEngine::SUB => mmio::OBJ_RAM_BASE_SUB,
+        };
+        Self {
+            units: Rc::new(RefCell::new(Units::new((size / (32 << bound)).min(MAX_OBJ_TILE + 1)))),
+            base,
+            bound,
+        }
+    }
+
+    /// Takes `bytes` of sprite tile memory, or returns `None` if there isn't a free space.
+    #[must_use]
+    pub fn alloc(&self, bytes: usize) -> Option<ObjTiles> {
+        let len = bytes.div_ceil(32 << self.bound);
+        let mut units = self.units.borrow_mut();
+        let start = units.find(len, 1, MAX_OBJ_TILE, false)?;
+        units.set(start, len, true);
+        Some(ObjTiles {
+            units: self.units.clone(),
+            addr: self.base + (start << (5 + self.bound)),
+            start,
+            len,
+            bound: self.bound,
+        })
+    }
+
+    /// Takes the memory for one frame of a sprite with this shape, size and colour format.
+    #[must_use]
+    #[inline]
+    pub fn alloc_sprite(&self, shape: ObjShape, size: ObjSize, palette: PaletteType) -> Option<ObjTiles> {
+        let (width, height) = size.dimensions(shape);
+        let pixels = width as usize * height as usize;
+        self.alloc(match palette {
+            PaletteType::Colors16 => pixels / 2,
+            PaletteType::Colors256 => pixels,
+        })
+    }
+
+    /// The number of free bytes. They might not all be next to each other.
+    #[must_use]
+    pub fn free_bytes(&self) -> usize {
+        self.units.borrow().free_count() << (5 + self.bound)
+    }
+}
+
+impl ObjTiles {
+    /// The tile number to use for a sprite's `tile`.
+    #[must_use]
+    #[inline]
+    pub fn tile(&self) -> u16 {
+        self.start as u16
+    }
+
+    /// Address of the start of the tile data.
+    #[must_use]
+    #[inline]
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Size of the memory in bytes (rounded up to a multiple of `32 << tile_obj_1d_bound`).
+    #[must_use]
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.len << (5 + self.bound)
+    }
+}
+
+impl Drop for ObjTiles {
+    fn drop(&mut self) {
+        self.units.borrow_mut().set(self.start, self.len, false);
+    }
+}
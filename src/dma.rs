@@ -0,0 +1,431 @@
+//! Module for using the DMA channels, to copy and fill memory without using the CPU.
+//!
+//! On the ARM9, DMA can't access the TCMs (ITCM and DTCM), and doesn't go through the data cache.
+//! The copy and fill functions here take care of the cache, but [`DmaChannel::start`] doesn't.
+//! The copy and fill functions wait for the channel to be free, so don't use the same channel
+//! from both the main program and an interrupt handler.
+//! See <https://www.problemkaputt.de/gbatek.htm#dsdmatransfers>
+
+use crate::interrupt::{IRQFlags, IRQType};
+use crate::mmio;
+use bitfield_struct::bitfield;
+use core::ptr::{read_volatile, write_volatile};
+#[cfg(all(feature = "arm7", not(feature = "arm9")))]
+use core::ptr::addr_of_mut;
+
+// Each channel has 3 registers: source address, destination address, control
+const CHANNEL_STRIDE: usize = mmio::DMA1SAD - mmio::DMA0SAD;
+const DMA_ENABLE: u32 = 1 << 31;
+#[cfg(feature = "arm9")]
+const DTCM_SIZE: usize = 16 * 1024;
+
+/// Panics if `bytes` bytes at `addr` overlap DTCM, which the ARM9 DMA can't access.
+#[inline(always)]
+fn assert_not_dtcm(addr: *const u8, bytes: usize) {
+    #[cfg(feature = "arm9")]
+    {
+        // start of DTCM (defined in the linkerscript)
+        extern "C" { static __dtcm_start: u8; }
+        let start = core::ptr::addr_of!(__dtcm_start) as usize;
+        let addr = addr as usize;
+        assert!(addr + bytes <= start || addr >= start + DTCM_SIZE, "DMA can't access DTCM (address: {addr:#X})");
+    }
+    #[cfg(not(feature = "arm9"))]
+    let _ = (addr, bytes);
+}
+
+/// One of the 4 DMA channels.
+///
+/// If several channels start at the same time, the lowest numbered channel goes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaChannel {
+    Dma0 = 0,
+    Dma1 = 1,
+    Dma2 = 2,
+    Dma3 = 3,
+}
+
+/// How a DMA address changes after each unit is transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrControl {
+    Increment = 0,
+    Decrement = 1,
+    Fixed = 2,
+    /// Increment during the transfer, and go back to the start address when it repeats.
+    /// Only valid for the destination address.
+    IncrementReload = 3,
+}
+
+impl From<u32> for AddrControl {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Increment,
+            1 => Self::Decrement,
+            2 => Self::Fixed,
+            _ => Self::IncrementReload,
+        }
+    }
+}
+
+impl From<AddrControl> for u32 {
+    fn from(value: AddrControl) -> Self {
+        value as u32
+    }
+}
+
+/// The size of each unit that a DMA transfer moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaUnit {
+    Bits16 = 0,
+    Bits32 = 1,
+}
+
+impl From<u32> for DmaUnit {
+    fn from(value: u32) -> Self {
+        if value == 0 { Self::Bits16 } else { Self::Bits32 }
+    }
+}
+
+impl From<DmaUnit> for u32 {
+    fn from(value: DmaUnit) -> Self {
+        value as u32
+    }
+}
+
+/// The event that starts a DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaTiming {
+    /// Start as soon as the channel is enabled.
+    Immediate,
+    /// Start at the beginning of VBlank.
+    VBlank,
+    /// Start at the beginning of each HBlank (not during VBlank). ARM9 only.
+    #[cfg(feature = "arm9")]
+    HBlank,
+    /// Start at the beginning of each visible line, in sync with the display. ARM9 only.
+    #[cfg(feature = "arm9")]
+    DisplayStart,
+    /// Feed the main memory display FIFO (display mode 3). ARM9 only.
+    #[cfg(feature = "arm9")]
+    MainMemoryDisplay,
+    /// Start when a DS cartridge transfer has a word ready.
+    DsCart,
+    /// Start when the GBA slot requests it.
+    GbaCart,
+    /// Start when the geometry command FIFO is less than half full. ARM9 only.
+    #[cfg(feature = "arm9")]
+    GeometryFifo,
+    /// Start when the wifi hardware requests it. Only on DMA1 and DMA3. ARM7 only.
+    #[cfg(not(feature = "arm9"))]
+    Wifi,
+}
+
+// The timing field is 3 bits on the ARM9, and 2 bits (starting 1 bit higher) on the ARM7.
+// On the ARM7 the lowest bit is the GBA slot DRQ flag, which is left at 0 here.
+#[cfg(feature = "arm9")]
+impl From<u32> for DmaTiming {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Immediate,
+            1 => Self::VBlank,
+            2 => Self::HBlank,
+            3 => Self::DisplayStart,
+            4 => Self::MainMemoryDisplay,
+            5 => Self::DsCart,
+            6 => Self::GbaCart,
+            _ => Self::GeometryFifo,
+        }
+    }
+}
+
+#[cfg(feature = "arm9")]
+impl From<DmaTiming> for u32 {
+    fn from(value: DmaTiming) -> Self {
+        match value {
+            DmaTiming::Immediate => 0,
+            DmaTiming::VBlank => 1,
+            DmaTiming::HBlank => 2,
+            DmaTiming::DisplayStart => 3,
+            DmaTiming::MainMemoryDisplay => 4,
+            DmaTiming::DsCart => 5,
+            DmaTiming::GbaCart => 6,
+            DmaTiming::GeometryFifo => 7,
+        }
+    }
+}
+
+#[cfg(not(feature = "arm9"))]
+impl From<u32> for DmaTiming {
+    fn from(value: u32) -> Self {
+        // 6 can also mean Wifi on DMA1 and DMA3, there's no way to tell without the channel
+        match value >> 1 {
+            0 => Self::Immediate,
+            1 => Self::VBlank,
+            2 => Self::DsCart,
+            _ => Self::GbaCart,
+        }
+    }
+}
+
+#[cfg(not(feature = "arm9"))]
+impl From<DmaTiming> for u32 {
+    fn from(value: DmaTiming) -> Self {
+        match value {
+            DmaTiming::Immediate => 0,
+            DmaTiming::VBlank => 2,
+            DmaTiming::DsCart => 4,
+            DmaTiming::GbaCart | DmaTiming::Wifi => 6,
+        }
+    }
+}
+
+/// The DMA control register (DMAxCNT).
+#[bitfield(u32)]
+pub struct DmaControl {
+    /// Number of units to transfer. 0 means the maximum (see [`DmaChannel::max_units`]).
+    #[bits(21)]
+    pub unit_count: u32,
+    #[bits(2)]
+    pub dest_control: AddrControl,
+    #[bits(2)]
+    pub src_control: AddrControl,
+    /// Start the transfer again every time the timing event happens, until the channel is stopped.
+    /// Not used with [`DmaTiming::Immediate`].
+    pub repeat: bool,
+    #[bits(1)]
+    pub unit: DmaUnit,
+    #[bits(3)]
+    pub timing: DmaTiming,
+    /// Request the DMA interrupt for this channel when the transfer finishes.
+    pub irq: bool,
+    pub enable: bool,
+}
+
+impl DmaChannel {
+    /// Gets the channel with this index (0-3).
+    #[must_use]
+    #[inline]
+    pub fn from_index(index: u32) -> Self {
+        debug_assert!(index <= 3, "DMA channel must be from 0 to 3 (was: {index})");
+        match index {
+            0 => Self::Dma0,
+            1 => Self::Dma1,
+            2 => Self::Dma2,
+            _ => Self::Dma3,
+        }
+    }
+
+    #[inline(always)]
+    fn base_addr(self) -> usize {
+        mmio::DMA0SAD + self as usize * CHANNEL_STRIDE
+    }
+
+    #[inline(always)]
+    fn control_addr(self) -> usize {
+        mmio::DMA0CNT_L + self as usize * CHANNEL_STRIDE
+    }
+
+    /// The highest number of units this channel can transfer at once.
+    #[must_use]
+    #[inline]
+    pub fn max_units(self) -> u32 {
+        #[cfg(feature = "arm9")]
+        { 0x200000 }
+        #[cfg(not(feature = "arm9"))]
+        { if self == Self::Dma3 { 0x10000 } else { 0x4000 } }
+    }
+
+    /// The interrupt requested when a transfer on this channel finishes, if [`DmaControl::irq`] was set.
+    ///
+    /// Use this with [`irq_set_handler`](crate::interrupt::irq_set_handler) to run code on completion.
+    #[must_use]
+    #[inline]
+    pub fn irq_type(self) -> IRQType {
+        match self {
+            Self::Dma0 => IRQType::DMA0,
+            Self::Dma1 => IRQType::DMA1,
+            Self::Dma2 => IRQType::DMA2,
+            Self::Dma3 => IRQType::DMA3,
+        }
+    }
+
+    /// The interrupt flag for this channel, to use with [`irq_enable`](crate::interrupt::irq_enable).
+    #[must_use]
+    #[inline]
+    pub fn irq_flag(self) -> IRQFlags {
+        IRQFlags::from_bits_truncate(1 << self.irq_type() as u32)
+    }
+
+    /// Sets up and starts a DMA transfer.
+    ///
+    /// The enable bit is always set, so the transfer starts right away, or when its timing event happens.
+    ///
+    /// # Safety
+    /// `src` and `dst` must be valid for the whole transfer (including repeats) and aligned to the unit size.
+    /// On the ARM9, any cached data in main RAM must be cleaned / invalidated by the caller (see [`crate::cache`]).
+    pub unsafe fn start(self, src: *const u8, dst: *mut u8, control: DmaControl) {
+        let base = self.base_addr();
+        write_volatile(base as *mut u32, src as u32);
+        write_volatile((base + 4) as *mut u32, dst as u32);
+        write_volatile(self.control_addr() as *mut u32, control.with_enable(true).into());
+    }
+
+    /// Gets the current value of the control register.
+    #[must_use]
+    #[inline]
+    pub fn control(self) -> DmaControl {
+        unsafe { read_volatile(self.control_addr() as *mut u32).into() }
+    }
+
+    /// Checks if this channel is enabled (transferring, or waiting for its timing event).
+    #[must_use]
+    #[inline]
+    pub fn is_busy(self) -> bool {
+        unsafe { read_volatile(self.control_addr() as *mut u32) & DMA_ENABLE != 0 }
+    }
+
+    /// Waits until the current transfer on this channel has finished.
+    ///
+    /// A repeating transfer never finishes, so this would wait forever. Use [`DmaChannel::stop`] instead.
+    #[inline]
+    pub fn wait(self) {
+        while self.is_busy() {}
+    }
+
+    /// Stops this channel, including repeating transfers.
+    #[inline]
+    pub fn stop(self) {
+        let cnt_h = self.control_addr() + 2;
+        unsafe { write_volatile(cnt_h as *mut u16, 0); }
+    }
+
+    /// Runs an immediate transfer, split into chunks that fit in the unit count, and waits for it.
+    ///
+    /// # Safety
+    /// Same as [`DmaChannel::start`]. `units` must not be 0.
+    unsafe fn transfer_blocking(self, mut src: *const u8, mut dst: *mut u8, mut units: u32, control: DmaControl) {
+        let unit_size = match control.unit() { DmaUnit::Bits16 => 2, DmaUnit::Bits32 => 4 };
+        let src_step = if control.src_control() == AddrControl::Fixed { 0 } else { unit_size };
+        self.wait();
+        while units > 0 {
+            let chunk = units.min(self.max_units());
+            // a count of max_units is written as 0
+            self.start(src, dst, control.with_unit_count(chunk & (self.max_units() - 1)));
+            self.wait();
+            src = src.add((chunk * src_step) as usize);
+            dst = dst.add((chunk * unit_size) as usize);
+            units -= chunk;
+        }
+    }
+
+    /// Copies `src` into `dst` using 16-bit units, and waits for it to finish.
+    ///
+    /// The slices must be the same length.
+    ///
+    /// # Panics
+    /// Panics if the slices are different lengths, or if either is in DTCM (like local variables on the ARM9).
+    pub fn copy16(self, src: &[u16], dst: &mut [u16]) {
+        assert!(src.len() == dst.len(), "DMA copy slices must be the same length");
+        unsafe { self.copy_raw(src.as_ptr().cast(), dst.as_mut_ptr().cast(), src.len() as u32, DmaUnit::Bits16); }
+    }
+
+    /// Copies `src` into `dst` using 32-bit units, and waits for it to finish.
+    ///
+    /// The slices must be the same length.
+    ///
+    /// # Panics
+    /// Panics if the slices are different lengths, or if either is in DTCM (like local variables on the ARM9).
+    pub fn copy32(self, src: &[u32], dst: &mut [u32]) {
+        assert!(src.len() == dst.len(), "DMA copy slices must be the same length");
+        unsafe { self.copy_raw(src.as_ptr().cast(), dst.as_mut_ptr().cast(), src.len() as u32, DmaUnit::Bits32); }
+    }
+
+    /// Copies `units` units from `src` to `dst`, and waits for it to finish. Handles the ARM9 data cache.
+    ///
+    /// Useful for copying to hardware like VRAM or OAM, which can't be made into a slice.
+    ///
+    /// # Panics
+    /// Panics if either range is in DTCM (like local variables on the ARM9).
+    ///
+    /// # Safety
+    /// `src` and `dst` must be valid for `units` units and aligned to the unit size, and must not overlap.
+    pub unsafe fn copy_raw(self, src: *const u8, dst: *mut u8, units: u32, unit: DmaUnit) {
+        if units == 0 {
+            return;
+        }
+        let bytes = units as usize * if unit == DmaUnit::Bits16 { 2 } else { 4 };
+        assert_not_dtcm(src, bytes);
+        assert_not_dtcm(dst, bytes);
+        #[cfg(feature = "arm9")]
+        {
+            crate::cache::clean_dcache_range(src, bytes);
+            crate::cache::flush_dcache_range(dst, bytes);
+        }
+        let control = DmaControl::new()
+            .with_src_control(AddrControl::Increment)
+            .with_dest_control(AddrControl::Increment)
+            .with_unit(unit)
+            .with_timing(DmaTiming::Immediate);
+        self.transfer_blocking(src, dst, units, control);
+    }
+
+    /// Fills `dst` with a 16-bit value, and waits for it to finish.
+    ///
+    /// # Panics
+    /// Panics if `dst` is in DTCM (like local variables on the ARM9).
+    pub fn fill16(self, value: u16, dst: &mut [u16]) {
+        unsafe { self.fill_raw(value as u32 | (value as u32) << 16, dst.as_mut_ptr().cast(), dst.len() as u32, DmaUnit::Bits16); }
+    }
+
+    /// Fills `dst` with a 32-bit value, and waits for it to finish.
+    ///
+    /// # Panics
+    /// Panics if `dst` is in DTCM (like local variables on the ARM9).
+    pub fn fill32(self, value: u32, dst: &mut [u32]) {
+        unsafe { self.fill_raw(value, dst.as_mut_ptr().cast(), dst.len() as u32, DmaUnit::Bits32); }
+    }
+
+    /// Fills `units` units at `dst` with a value, and waits for it to finish. Handles the ARM9 data cache.
+    ///
+    /// For 16-bit units, the low half of `value` is used.
+    ///
+    /// # Panics
+    /// Panics if the range is in DTCM (like local variables on the ARM9).
+    ///
+    /// # Safety
+    /// `dst` must be valid for `units` units and aligned to the unit size.
+    pub unsafe fn fill_raw(self, value: u32, dst: *mut u8, units: u32, unit: DmaUnit) {
+        if units == 0 {
+            return;
+        }
+        let bytes = units as usize * if unit == DmaUnit::Bits16 { 2 } else { 4 };
+        assert_not_dtcm(dst, bytes);
+        #[cfg(feature = "arm9")]
+        crate::cache::flush_dcache_range(dst, bytes);
+        let control = DmaControl::new()
+            .with_src_control(AddrControl::Fixed)
+            .with_dest_control(AddrControl::Increment)
+            .with_unit(unit)
+            .with_timing(DmaTiming::Immediate);
+        self.transfer_blocking(self.fill_source(value), dst, units, control);
+    }
+
+    /// Sets up a location holding `value` for a fill, and returns its address.
+    #[cfg(feature = "arm9")]
+    unsafe fn fill_source(self, value: u32) -> *const u8 {
+        // the ARM9 has a register for this, so the value doesn't have to go through the cache
+        let addr = mmio::DMA0FILL + self as usize * 4;
+        write_volatile(addr as *mut u32, value);
+        addr as *const u8
+    }
+
+    /// Sets up a location holding `value` for a fill, and returns its address.
+    #[cfg(not(feature = "arm9"))]
+    unsafe fn fill_source(self, value: u32) -> *const u8 {
+        // one word per channel, so fills on different channels (or from an interrupt) don't clash
+        static mut FILL_VALUES: [u32; 4] = [0; 4];
+        let addr = addr_of_mut!(FILL_VALUES[self as usize]);
+        write_volatile(addr, value);
+        addr as *const u8
+    }
+}
@@ -4,6 +4,13 @@
 use crate::{mmio, shared};
 use bitflags::bitflags;
 use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+#[cfg(feature = "arm7")]
+use crate::interrupt::critical_section;
+#[cfg(feature = "arm7")]
+use crate::spi::{self, SpiBaudrate, SpiDevice};
+#[cfg(feature = "arm7")]
+use crate::sync::NdsCell;
 
 bitflags! {
     #[repr(transparent)]
@@ -47,3 +54,326 @@ pub fn read_keys() -> Buttons {
         Buttons::from_bits_retain(ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.buttons.0.bits)))
     }
 }
+
+/// Tracks the buttons from frame to frame, to find which were just pressed or released.
+///
+/// Call [`update`](KeyState::update) once per frame (e.g. after waiting for VBlank),
+/// then use the other functions to check the buttons.
+///
+/// # Examples
+///
+/// ```
+/// let mut keys = KeyState::new();
+/// loop {
+///     wait_for_vblank();
+///     keys.update();
+///     if keys.keys_down().contains(Buttons::A) {
+///         // A was pressed this frame
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy)]
+pub struct KeyState {
+    held: Buttons,
+    prev: Buttons,
+    repeat: Buttons,
+    repeat_delay: u16,
+    repeat_interval: u16,
+    repeat_timer: u16,
+}
+
+impl KeyState {
+    /// Creates a new key state, with an auto-repeat delay of 30 frames and interval of 15 frames.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            held: Buttons::empty(),
+            prev: Buttons::empty(),
+            repeat: Buttons::empty(),
+            repeat_delay: 30,
+            repeat_interval: 15,
+            repeat_timer: 30,
+        }
+    }
+
+    /// Reads the buttons with [`read_keys`], and updates the state. Should be called once per frame.
+    pub fn update(&mut self) {
+        self.prev = self.held;
+        self.held = read_keys();
+
+        if self.held != self.prev || self.repeat_delay == 0 {
+            // the buttons changed, so restart the repeat delay
+            self.repeat_timer = self.repeat_delay;
+            self.repeat = self.keys_down();
+        } else {
+            self.repeat_timer = self.repeat_timer.saturating_sub(1);
+            if self.repeat_timer == 0 {
+                self.repeat_timer = self.repeat_interval.max(1);
+                self.repeat = self.held;
+            } else {
+                self.repeat = Buttons::empty();
+            }
+        }
+    }
+
+    /// Buttons that are currently pressed.
+    #[must_use]
+    #[inline]
+    pub fn keys_held(&self) -> Buttons {
+        self.held
+    }
+
+    /// Buttons that were pressed this frame, but not the last.
+    #[must_use]
+    #[inline]
+    pub fn keys_down(&self) -> Buttons {
+        self.held & !self.prev
+    }
+
+    /// Buttons that were released this frame.
+    #[must_use]
+    #[inline]
+    pub fn keys_up(&self) -> Buttons {
+        self.prev & !self.held
+    }
+
+    /// Like [`keys_down`](KeyState::keys_down), but held buttons are also repeated.
+    ///
+    /// After a button has been held for the repeat delay, it is returned again every repeat interval,
+    /// which is useful for scrolling through menus.
+    #[must_use]
+    #[inline]
+    pub fn keys_down_repeat(&self) -> Buttons {
+        self.repeat
+    }
+
+    /// Sets the auto-repeat timing, in frames.
+    ///
+    /// `delay` is how long a button must be held before it starts repeating,
+    /// and `interval` is the time between each repeat after that.
+    /// A delay of 0 disables auto-repeat.
+    pub fn set_repeat(&mut self, delay: u16, interval: u16) {
+        self.repeat_delay = delay;
+        self.repeat_interval = interval;
+        self.repeat_timer = delay;
+    }
+}
+
+impl Default for KeyState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A position on the touchscreen.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TouchPosition {
+    /// X position in pixels, from 0 to 255.
+    pub px: u16,
+    /// Y position in pixels, from 0 to 191.
+    pub py: u16,
+    /// X position as read from the touchscreen controller (12 bit).
+    pub raw_x: u16,
+    /// Y position as read from the touchscreen controller (12 bit).
+    pub raw_y: u16,
+    /// Pressure measurement Z1 (12 bit).
+    pub z1: u16,
+    /// Pressure measurement Z2 (12 bit).
+    pub z2: u16,
+}
+
+impl TouchPosition {
+    pub(crate) const fn new() -> Self {
+        Self { px: 0, py: 0, raw_x: 0, raw_y: 0, z1: 0, z2: 0 }
+    }
+}
+
+// https://www.problemkaputt.de/gbatek.htm#dstouchscreencontrollertsc
+#[cfg(feature = "arm7")]
+const TSC_MEASURE_Y: u8 = 0x90;
+#[cfg(feature = "arm7")]
+const TSC_MEASURE_Z1: u8 = 0xB4;
+#[cfg(feature = "arm7")]
+const TSC_MEASURE_Z2: u8 = 0xC4;
+#[cfg(feature = "arm7")]
+const TSC_MEASURE_X: u8 = 0xD0;
+// EXTKEYIN bit that is 0 while the screen is touched
+#[cfg(feature = "arm7")]
+const EXTKEY_PEN: u16 = 1 << 6;
+// how many times each axis is read, and how far apart the closest two readings can be
+#[cfg(feature = "arm7")]
+const TOUCH_SAMPLES: usize = 5;
+#[cfg(feature = "arm7")]
+const TOUCH_MAX_NOISE: u16 = 30;
+
+// Calibration from the firmware user settings, as fixed point with 19 fractional bits.
+// Set up by touch_init, then only read by scan_touch.
+#[cfg(feature = "arm7")]
+static TOUCH_X_SCALE: NdsCell<i32> = NdsCell::new(0);
+#[cfg(feature = "arm7")]
+static TOUCH_Y_SCALE: NdsCell<i32> = NdsCell::new(0);
+#[cfg(feature = "arm7")]
+static TOUCH_X_OFFSET: NdsCell<i32> = NdsCell::new(0);
+#[cfg(feature = "arm7")]
+static TOUCH_Y_OFFSET: NdsCell<i32> = NdsCell::new(0);
+
+/// CRC16 used by the firmware user settings (same as the BIOS GetCRC16 function).
+#[cfg(feature = "arm7")]
+fn crc16(data: &[u8]) -> u16 {
+    const VAL: [u16; 8] = [0xC0C1, 0xC181, 0xC301, 0xC601, 0xCC01, 0xD801, 0xF001, 0xA001];
+    let mut crc: u16 = 0xFFFF;
+    for &b in data {
+        crc ^= b as u16;
+        for (j, v) in VAL.iter().enumerate() {
+            let carry = crc & 1 != 0;
+            crc >>= 1;
+            if carry { crc ^= v << (7 - j); }
+        }
+    }
+    crc
+}
+
+/// Reads the touchscreen calibration out of the firmware user settings.
+/// Called in `lib_init`.
+// https://www.problemkaputt.de/gbatek.htm#dsfirmwareusersettings
+#[cfg(feature = "arm7")]
+pub(crate) fn touch_init() {
+    let mut header = [0u8; 2];
+    spi::read_firmware(0x20, &mut header);
+    let settings_addr = u16::from_le_bytes(header) as u32 * 8;
+
+    // There are two copies of the user settings, use the newest one that isn't corrupted
+    let mut copies = [[0u8; 0x74]; 2];
+    spi::read_firmware(settings_addr, &mut copies[0]);
+    spi::read_firmware(settings_addr + 0x100, &mut copies[1]);
+    let valid = |c: &[u8; 0x74]| crc16(&c[..0x70]) == u16::from_le_bytes([c[0x72], c[0x73]]);
+    let count = |c: &[u8; 0x74]| u16::from_le_bytes([c[0x70], c[0x71]]);
+    let settings = match (valid(&copies[0]), valid(&copies[1])) {
+        (true, true) if count(&copies[1]).wrapping_sub(count(&copies[0])) & 0x7F == 1 => &copies[1],
+        (false, true) => &copies[1],
+        _ => &copies[0],
+    };
+
+    let adc_x1 = u16::from_le_bytes([settings[0x58], settings[0x59]]) as i32;
+    let adc_y1 = u16::from_le_bytes([settings[0x5A], settings[0x5B]]) as i32;
+    let scr_x1 = settings[0x5C] as i32;
+    let scr_y1 = settings[0x5D] as i32;
+    let adc_x2 = u16::from_le_bytes([settings[0x5E], settings[0x5F]]) as i32;
+    let adc_y2 = u16::from_le_bytes([settings[0x60], settings[0x61]]) as i32;
+    let scr_x2 = settings[0x62] as i32;
+    let scr_y2 = settings[0x63] as i32;
+
+    // max(1) so broken calibration data can't cause a divide by 0
+    let x_scale = ((scr_x2 - scr_x1) << 19) / (adc_x2 - adc_x1).max(1);
+    let y_scale = ((scr_y2 - scr_y1) << 19) / (adc_y2 - adc_y1).max(1);
+    TOUCH_X_SCALE.write(x_scale);
+    TOUCH_Y_SCALE.write(y_scale);
+    TOUCH_X_OFFSET.write(((adc_x1 + adc_x2) * x_scale - ((scr_x1 + scr_x2) << 19)) / 2);
+    TOUCH_Y_OFFSET.write(((adc_y1 + adc_y2) * y_scale - ((scr_y1 + scr_y2) << 19)) / 2);
+}
+
+/// Does one 12 bit measurement with the touchscreen controller.
+#[cfg(feature = "arm7")]
+fn tsc_read(command: u8) -> u16 {
+    spi::transfer(SpiDevice::Touchscreen, SpiBaudrate::Baud2MHz, true, command);
+    let high = spi::transfer(SpiDevice::Touchscreen, SpiBaudrate::Baud2MHz, true, 0) as u16;
+    let low = spi::transfer(SpiDevice::Touchscreen, SpiBaudrate::Baud2MHz, false, 0) as u16;
+    ((high & 0x7F) << 5) | (low >> 3)
+}
+
+/// Reads a touchscreen value several times, and averages the two closest readings.
+///
+/// Returns `None` if the readings are too far apart (the pen was moving or lifted).
+#[cfg(feature = "arm7")]
+fn tsc_read_filtered(command: u8) -> Option<u16> {
+    let mut samples = [0u16; TOUCH_SAMPLES];
+    for s in samples.iter_mut() {
+        *s = tsc_read(command);
+    }
+    let mut best: Option<(u16, u16)> = None; // (difference, average)
+    for i in 0..TOUCH_SAMPLES {
+        for j in (i + 1)..TOUCH_SAMPLES {
+            let diff = samples[i].abs_diff(samples[j]);
+            if best.is_none_or(|(d, _)| diff < d) {
+                best = Some((diff, (samples[i] + samples[j]) / 2));
+            }
+        }
+    }
+    match best {
+        Some((diff, avg)) if diff <= TOUCH_MAX_NOISE && avg != 0 && avg != 0xFFF => Some(avg),
+        _ => None,
+    }
+}
+
+/// Reads the touchscreen, and updates the shared touch position.
+///
+/// If the reading is too noisy, the previous position is kept.  
+/// Only usable on ARM7.
+#[cfg(feature = "arm7")]
+pub fn scan_touch() {
+    let pen_down = || mmio::EXTKEYIN.read() & EXTKEY_PEN == 0;
+    if !pen_down() {
+        publish_touch(false, None);
+        return;
+    }
+
+    let mut reading = None;
+    critical_section!({
+        if let (Some(raw_x), Some(raw_y)) = (tsc_read_filtered(TSC_MEASURE_X), tsc_read_filtered(TSC_MEASURE_Y)) {
+            reading = Some((raw_x, raw_y, tsc_read(TSC_MEASURE_Z1), tsc_read(TSC_MEASURE_Z2)));
+        }
+    });
+    // if the pen was lifted during the reading, the values are garbage
+    let Some((raw_x, raw_y, z1, z2)) = reading.filter(|_| pen_down()) else { return; };
+
+    let (x_scale, y_scale) = (TOUCH_X_SCALE.read(), TOUCH_Y_SCALE.read());
+    let px = (raw_x as i32 * x_scale - TOUCH_X_OFFSET.read() + x_scale / 2) >> 19;
+    let py = (raw_y as i32 * y_scale - TOUCH_Y_OFFSET.read() + y_scale / 2) >> 19;
+    publish_touch(true, Some(TouchPosition {
+        px: px.clamp(0, 255) as u16,
+        py: py.clamp(0, 191) as u16,
+        raw_x,
+        raw_y,
+        z1,
+        z2,
+    }));
+}
+
+// The ARM9 could read the shared touch data while it's half written, so a sequence number is
+// used: it's odd while the data is being written, and read_touch retries if it changed.
+#[cfg(feature = "arm7")]
+fn publish_touch(pressed: bool, pos: Option<TouchPosition>) {
+    unsafe {
+        let seq = ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.touch_seq));
+        ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.touch_seq), seq.wrapping_add(1));
+        compiler_fence(Ordering::SeqCst);
+        ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.touch_pressed), pressed);
+        if let Some(pos) = pos {
+            ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.touch), pos);
+        }
+        compiler_fence(Ordering::SeqCst);
+        ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.touch_seq), seq.wrapping_add(2));
+    }
+}
+
+/// Returns the current touch position, or `None` if the screen isn't being touched.
+///
+/// The ARM7 must be calling [`scan_touch`] to update the position.
+pub fn read_touch() -> Option<TouchPosition> {
+    loop {
+        unsafe {
+            let seq = ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.touch_seq));
+            if seq & 1 != 0 {
+                continue;
+            }
+            compiler_fence(Ordering::SeqCst);
+            let pressed = ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.touch_pressed));
+            let pos = ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.touch));
+            compiler_fence(Ordering::SeqCst);
+            if ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.touch_seq)) == seq {
+                return if pressed { Some(pos) } else { None };
+            }
+        }
+    }
+}
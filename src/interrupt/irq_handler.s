@@ -24,11 +24,6 @@ irq_handler:
     orr r1, r1, r0      // r1 |= (IE & IF)
     str r1, [r2]        // write new BIOS IF
 
-    ldr r1, =USER_IRQ_HANDLER
-    ldr r1, [r1] // load the actual function pointer into r0
-    cmp r1, #0   // is it 0 / None?
-    beq 3f       // branch if so
-
     mrs r2, spsr
     push {r2, r3, lr} // save SPSR_IRQ, OLD_IME and LR_IRQ (IRQ SPSR and LR needed for nested interrupts)
     
@@ -38,23 +33,59 @@ irq_handler:
     orr r2, r2, #0x1F // Switch to System mode
     msr cpsr, r2
 
-    push {r3, lr} // save IRQ CPSR and System LR to System stack
+    // save IRQ CPSR, the registers used by the loop and System LR to System stack
+    // (r7 is unused, but pushing an even number of registers keeps SP 8 byte aligned for the handlers, as AAPCS requires)
+    push {r3-r7, lr}
+
+    // Count this dispatch, so handlers replaced while it runs aren't freed until it's finished
+    ldr r1, =IRQ_DISPATCH_DEPTH
+    ldr r2, [r1]
+    add r2, r2, #1
+    str r2, [r1]
 
-    // TODO: the AAPCS ABI technically says the stack should be 64 bit aligned here. maybe should do that?
-    // r0 = (IE & IF), passed into the user function
-    adr lr, 2f
-    bx r1 // jump to user handler
-2:
+    // Mark the requested IRQs as waiting for their handlers (see irq_acknowledge)
+    ldr r4, =IRQ_UNHANDLED // r4 = address of the IRQs that are waiting for their handlers
+    ldr r2, [r4]
+    orr r2, r2, r0
+    str r2, [r4]
+
+    // Run the handler for each requested IRQ, in the order given by IRQ_PRIORITY_ORDER
+    ldr r5, =IRQ_PRIORITY_ORDER // r5 = pointer to the next IRQ number to check
+    ldr r6, =IRQ_HANDLER_TABLE  // r6 = handler table (12 bytes per entry)
+4:
+    ldrb r1, [r5], #1 // r1 = next IRQ number
+    cmp r1, #32
+    bhs 6f            // reached the 0xFF at the end of the list
+    mov r2, #1
+    mov r2, r2, lsl r1 // r2 = bit for this IRQ
+    ldr r0, [r4]
+    tst r0, r2         // is this IRQ still waiting for its handler?
+    beq 4b
+    bic r0, r0, r2
+    str r0, [r4]       // it's being handled now
+    add r2, r1, r1, lsl #1 // r2 = IRQ number * 3
+    add r2, r6, r2, lsl #2 // r2 = address of handler table entry
+    ldmia r2, {r0, r1}     // r0 = handler data, r1 = handler call function
+    cmp r1, #0             // is there a handler?
+    beq 4b
+    adr lr, 4b
+    bx r1 // jump to handler (returns to the start of the loop)
+6:
     // Disable IME while we mess with stuff
     mov r12, #0x4000000
     str r12, [r12, #0x208]
 
-    pop {r2, lr} // restore IRQ CPSR and System LR from System stack
-    msr cpsr, r2 // go back to IRQ mode
+    // End the dispatch, freeing any handlers that were replaced while it ran
+    ldr r1, =irq_dispatch_end
+    adr lr, 7f
+    bx r1
+7:
+    pop {r3-r7, lr} // restore IRQ CPSR, loop registers and System LR from System stack
+    msr cpsr, r3 // go back to IRQ mode
 
     pop {r1, r3, lr} // restore SPSR_IRQ, OLD_IME and LR_IRQ from IRQ stack
     msr spsr, r1
-3:
+
     // Restore IME
     mov r12, #0x4000000
     str r3, [r12, #0x208]
@@ -4,11 +4,16 @@
 // https://github.com/embassy-rs/atomic-polyfill
 // or could provide a custom impl for the critical-section crate:
 // https://github.com/embassy-rs/critical-section
+use crate::ipc::SYNC_IRQ_ENABLE;
+use crate::timers::{self, Prescaler, Timer};
 use crate::mmio;
-use crate::sync::{NdsCell, NdsCellSafe};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use bitflags::bitflags;
-use core::arch::global_asm;
-use core::ptr::{read_volatile, write_volatile};
+use core::arch::{asm, global_asm};
+use core::cell::UnsafeCell;
+use core::ptr::{self, read_volatile, write_volatile};
+use core::time::Duration;
 
 global_asm! {
     include_str!("irq_handler.s"),
@@ -54,11 +59,7 @@ pub macro critical_section($code:block) {
     if e { enable_ime(); }
 }
 
-#[no_mangle]
-#[cfg_attr(feature = "arm9", link_section = ".itcm.irq_handler_ptr")]
-static USER_IRQ_HANDLER: NdsCell<Option<extern "C" fn(IRQFlags)>> = NdsCell::new(None);
-unsafe impl NdsCellSafe for Option<extern "C" fn(IRQFlags)> {}
-
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IRQType {
     Vblank = 0,
     Hblank = 1,
@@ -118,9 +119,159 @@ bitflags! {
     }
 }
 
-#[inline(always)]
-pub fn irq_set_handler(f: Option<extern "C" fn(IRQFlags)>) {
-    USER_IRQ_HANDLER.write(f);
+/// The number of entries in the handler table (one for each [`IRQType`]).
+const IRQ_COUNT: usize = 25;
+
+// One entry of the handler table. irq_handler.s loads `data` and `call` with a single ldm,
+// so the order of these fields matters.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IRQHandlerEntry {
+    data: *mut (),
+    call: Option<unsafe extern "C" fn(*mut ())>,
+    drop: Option<unsafe fn(*mut ())>,
+}
+
+const EMPTY_HANDLER: IRQHandlerEntry = IRQHandlerEntry { data: ptr::null_mut(), call: None, drop: None };
+
+#[repr(transparent)]
+struct IRQHandlerTable(UnsafeCell<[IRQHandlerEntry; IRQ_COUNT]>);
+// only modified in critical sections, and only read by the IRQ handler
+unsafe impl Sync for IRQHandlerTable {}
+
+#[no_mangle]
+#[cfg_attr(feature = "arm9", link_section = ".itcm.irq_handler_table")]
+static IRQ_HANDLER_TABLE: IRQHandlerTable = IRQHandlerTable(UnsafeCell::new([EMPTY_HANDLER; IRQ_COUNT]));
+
+// The order that irq_handler.s checks for pending interrupts and runs their handlers, ending with 0xFF.
+// Things with tight timing (the current scanline, timers) come first, then VBlank, then everything else.
+#[no_mangle]
+#[cfg_attr(feature = "arm9", link_section = ".itcm.irq_priority_order")]
+static IRQ_PRIORITY_ORDER: [u8; IRQ_COUNT - 2 + 1] = [
+    IRQType::Hblank as u8,
+    IRQType::Vcount as u8,
+    IRQType::Timer0 as u8,
+    IRQType::Timer1 as u8,
+    IRQType::Timer2 as u8,
+    IRQType::Timer3 as u8,
+    IRQType::Vblank as u8,
+    IRQType::IPCRecvFifoNotEmpty as u8,
+    IRQType::IPCSendFifoEmpty as u8,
+    IRQType::IPCSync as u8,
+    IRQType::DMA0 as u8,
+    IRQType::DMA1 as u8,
+    IRQType::DMA2 as u8,
+    IRQType::DMA3 as u8,
+    IRQType::GeometryFIFO as u8,
+    IRQType::CartTransfer as u8,
+    IRQType::CartIREQ as u8,
+    IRQType::Serial as u8,
+    IRQType::SPI as u8,
+    IRQType::Wifi as u8,
+    IRQType::Keypad as u8,
+    IRQType::Lid as u8,
+    IRQType::Slot2 as u8,
+    0xFF,
+];
+
+// Number of irq_handler.s dispatches running (more than 1 if a handler re-enables interrupts).
+// Incremented by irq_handler.s, and decremented by irq_dispatch_end.
+#[no_mangle]
+static mut IRQ_DISPATCH_DEPTH: u32 = 0;
+
+// IRQs that have been acknowledged in IF by irq_handler.s, but whose handlers haven't started yet.
+// irq_handler.s sets these at the start of a dispatch, and clears each one just before running its handler.
+#[no_mangle]
+static mut IRQ_UNHANDLED: u32 = 0;
+
+#[repr(transparent)]
+struct PendingDrops(UnsafeCell<Vec<IRQHandlerEntry>>);
+// only modified in critical sections
+unsafe impl Sync for PendingDrops {}
+
+// Handlers replaced while a dispatch was running. One of them might be the handler that replaced
+// itself, so they can't be freed until the dispatch is finished.
+static PENDING_DROPS: PendingDrops = PendingDrops(UnsafeCell::new(Vec::new()));
+
+/// Called by irq_handler.s after running the handlers, with IME disabled.
+#[no_mangle]
+extern "C" fn irq_dispatch_end() {
+    let pending = unsafe {
+        let depth = ptr::addr_of_mut!(IRQ_DISPATCH_DEPTH);
+        write_volatile(depth, read_volatile(depth) - 1);
+        if read_volatile(depth) != 0 {
+            return;
+        }
+        core::mem::take(&mut *PENDING_DROPS.0.get())
+    };
+    for entry in pending {
+        if let Some(drop) = entry.drop {
+            unsafe { drop(entry.data); }
+        }
+    }
+}
+
+unsafe extern "C" fn call_handler<F: FnMut()>(data: *mut ()) {
+    (*(data as *mut F))();
+}
+
+unsafe fn drop_handler<F>(data: *mut ()) {
+    drop(Box::from_raw(data as *mut F));
+}
+
+fn replace_handler(irq: IRQType, entry: IRQHandlerEntry) {
+    let mut old;
+    critical_section!({
+        let table = unsafe { &mut *IRQ_HANDLER_TABLE.0.get() };
+        old = core::mem::replace(&mut table[irq as usize], entry);
+        // the old handler might be the one that's running, so free it once the dispatch is finished
+        if old.drop.is_some() && unsafe { read_volatile(ptr::addr_of!(IRQ_DISPATCH_DEPTH)) } != 0 {
+            unsafe { (*PENDING_DROPS.0.get()).push(old); }
+            old = EMPTY_HANDLER;
+        }
+    });
+    // free the old closure after the critical section, so interrupts aren't held up
+    if let Some(drop) = old.drop {
+        unsafe { drop(old.data); }
+    }
+}
+
+/// Sets the function that runs when a certain interrupt happens.
+///
+/// The handler can be a plain function or a closure. Closures that capture variables are
+/// stored on the heap, functions and closures that don't capture anything don't allocate.  
+/// This replaces any handler previously set for that interrupt. Handlers can be replaced or removed
+/// from inside an interrupt handler (even their own), the old one is freed once all the handlers have run.
+///
+/// When several interrupts happen at once, their handlers run in this order:  
+/// HBlank, VCount, Timer 0-3, VBlank, IPC (receive FIFO, send FIFO, sync), DMA 0-3,
+/// Geometry FIFO, Cart Transfer, Cart IREQ, Serial, SPI, Wifi, Keypad, Lid, Slot 2.
+///
+/// The library uses the IPC interrupt handlers for [`crate::ipc`], so replacing those
+/// will break the IPC functions.  
+/// Setting a handler doesn't enable the interrupt, use [`irq_enable`] for that.
+///
+/// # Examples
+///
+/// ```
+/// irq_set_handler(IRQType::Vblank, || {
+///     // runs every frame
+/// });
+/// irq_enable(IRQFlags::VBLANK);
+/// ```
+pub fn irq_set_handler<F: FnMut() + 'static>(irq: IRQType, handler: F) {
+    replace_handler(irq, IRQHandlerEntry {
+        data: Box::into_raw(Box::new(handler)) as *mut (),
+        call: Some(call_handler::<F>),
+        drop: Some(drop_handler::<F>),
+    });
+}
+
+/// Removes the handler for an interrupt, so nothing runs when it happens.
+///
+/// This doesn't disable the interrupt, use [`irq_disable`] for that.
+pub fn irq_remove_handler(irq: IRQType) {
+    replace_handler(irq, EMPTY_HANDLER);
 }
 
 pub fn irq_enable(flags: IRQFlags) {
@@ -135,8 +286,10 @@ pub fn irq_enable(flags: IRQFlags) {
         if flags & IRQFlags::VCOUNT == IRQFlags::VCOUNT {
             mmio::DISPSTAT.write(mmio::DISPSTAT.read() | (1 << 5));
         }
-        // todo: also enable IPCSync
-        // also make sure cpsr irq thing is enabled??
+        if flags & IRQFlags::IPC_SYNC == IRQFlags::IPC_SYNC {
+            unsafe { write_volatile(mmio::IPCSYNC as *mut u16, read_volatile(mmio::IPCSYNC as *mut u16) | SYNC_IRQ_ENABLE); }
+        }
+        // todo: make sure cpsr irq thing is enabled??
     });
 }
 
@@ -152,8 +305,155 @@ pub fn irq_disable(flags: IRQFlags) {
         if flags & IRQFlags::VCOUNT == IRQFlags::VCOUNT {
             mmio::DISPSTAT.write(mmio::DISPSTAT.read() & !(1 << 5));
         }
-        // todo: also disable IPCSync
+        if flags & IRQFlags::IPC_SYNC == IRQFlags::IPC_SYNC {
+            unsafe { write_volatile(mmio::IPCSYNC as *mut u16, read_volatile(mmio::IPCSYNC as *mut u16) & !SYNC_IRQ_ENABLE); }
+        }
+    });
+}
+
+// The BIOS IRQ flags, set by irq_handler.s when an interrupt happens (defined in the linkerscript)
+#[inline(always)]
+fn irq_flags() -> *mut u32 {
+    extern "C" { static mut __irq_flags: u32; }
+    ptr::addr_of_mut!(__irq_flags)
+}
+
+/// Checks if any of `flags` has been requested, but its handler hasn't started running yet.
+///
+/// Unlike reading IF, this also covers interrupts that irq_handler.s has already acknowledged,
+/// but hasn't got to yet (because a higher priority handler is running). Call with IME disabled.
+pub(crate) fn irq_is_pending(flags: IRQFlags) -> bool {
+    let requested = unsafe { read_volatile(mmio::IF as *mut u32) | read_volatile(ptr::addr_of!(IRQ_UNHANDLED)) };
+    requested & flags.bits() != 0
+}
+
+/// Acknowledges any pending `flags` without running their handlers, as if the handlers had already run.
+///
+/// Call with IME disabled.
+pub(crate) fn irq_acknowledge(flags: IRQFlags) {
+    unsafe {
+        write_volatile(mmio::IF as *mut u32, flags.bits());
+        let unhandled = ptr::addr_of_mut!(IRQ_UNHANDLED);
+        write_volatile(unhandled, read_volatile(unhandled) & !flags.bits());
+        let irq_flags = irq_flags();
+        write_volatile(irq_flags, read_volatile(irq_flags) & !flags.bits());
+    }
+}
+
+/// Disables IRQs in the CPSR (not IME), and returns the old CPSR to pass to `restore_cpsr`.
+#[instruction_set(arm::a32)]
+#[inline(never)]
+fn disable_cpsr_irq() -> u32 {
+    let old: u32;
+    unsafe {
+        asm!(
+            "mrs {old}, cpsr",
+            "orr {tmp}, {old}, #0x80",
+            "msr cpsr_c, {tmp}",
+            old = out(reg) old,
+            tmp = out(reg) _,
+            options(nostack, preserves_flags)
+        );
+    }
+    old
+}
+
+#[instruction_set(arm::a32)]
+#[inline(never)]
+fn restore_cpsr(old: u32) {
+    unsafe {
+        asm!(
+            "msr cpsr_c, {old}",
+            old = in(reg) old,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Sleeps until an interrupt is requested. Works even when IRQs are disabled in the CPSR.
+#[cfg(feature = "arm9")]
+#[instruction_set(arm::a32)]
+#[inline(never)]
+fn cpu_halt() {
+    // CP15 "wait for interrupt"
+    unsafe {
+        asm!(
+            "mcr p15, 0, {zero}, c7, c0, 4",
+            zero = in(reg) 0,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+#[cfg(all(feature = "arm7", not(feature = "arm9")))]
+#[inline(always)]
+fn cpu_halt() {
+    crate::syscall::halt();
+}
+
+/// Waits for one of the interrupts in `flags` to happen, and returns the ones that did.
+///
+/// This is a replacement for the BIOS `IntrWait` function, which is bugged.  
+/// If `discard_old` is true, interrupts that happened before this was called are ignored,
+/// and it waits for a new one. If it's false, it returns immediately if one of the
+/// interrupts has already happened since the last wait.  
+/// Make sure interrupts are enabled before calling this!
+pub fn intr_wait(flags: IRQFlags, discard_old: bool) -> IRQFlags {
+    let irq_flags = irq_flags();
+    if discard_old {
+        critical_section!({
+            unsafe { write_volatile(irq_flags, read_volatile(irq_flags) & !flags.bits()); }
+        });
+    }
+    loop {
+        // Disabling IRQs in the CPSR means the check and the halt can't be split up by the
+        // IRQ handler. The CPU still wakes up from the halt, and runs the handler after the CPSR is restored.
+        let cpsr = disable_cpsr_irq();
+        let set = unsafe { read_volatile(irq_flags) } & flags.bits();
+        if set != 0 {
+            unsafe { write_volatile(irq_flags, read_volatile(irq_flags) & !set); }
+            restore_cpsr(cpsr);
+            return IRQFlags::from_bits_retain(set);
+        }
+        cpu_halt();
+        restore_cpsr(cpsr);
+    }
+}
+
+/// Like [`intr_wait`], but gives up if none of the interrupts happen within `timeout`.
+///
+/// Returns the interrupts that happened, or `None` if it timed out.  
+/// Uses `timer` to measure the timeout, and leaves it stopped afterwards. If it has an overflow handler,
+/// that runs when the timeout is reached.
+/// The timeout can be up to 2 seconds, with a resolution of about 30 microseconds.
+pub fn intr_wait_timeout(flags: IRQFlags, discard_old: bool, timer: &mut Timer, timeout: Duration) -> Option<IRQFlags> {
+    let timer_flag = timer.irq_flag();
+    let ticks = (timeout.as_micros() * (timers::TIMER_FREQ / 1024) as u128 / 1_000_000).clamp(1, 0x10000);
+    debug_assert!(timeout.as_micros() <= 2_000_000, "timeout for intr_wait_timeout must be 2 seconds or less");
+
+    let timer_was_enabled = unsafe { read_volatile(mmio::IE as *mut u32) } & timer_flag.bits() != 0;
+    let timer_irq_was_enabled = timer.irq_enabled();
+    // make sure an old overflow doesn't count as a timeout
+    let irq_flags = irq_flags();
+    critical_section!({
+        unsafe { write_volatile(irq_flags, read_volatile(irq_flags) & !timer_flag.bits()); }
     });
+    timer.set_irq_enabled(true);
+    timer.start(Prescaler::Div1024, (0x10000 - ticks) as u16);
+    irq_enable(timer_flag);
+
+    let set = intr_wait(flags | timer_flag, discard_old);
+
+    timer.stop();
+    timer.set_irq_enabled(timer_irq_was_enabled);
+    if !timer_was_enabled {
+        irq_disable(timer_flag);
+    }
+    if set.intersects(flags) {
+        Some(set & flags)
+    } else {
+        None
+    }
 }
 
 /// Wait for an interrupt to occur, then continue.
@@ -161,8 +461,7 @@ pub fn irq_disable(flags: IRQFlags) {
 /// Waits for one of the interrupts specified in `flags` to happen.  
 /// Make sure interrupts are enabled before calling this!
 pub fn wait_for_interrupt(flags: IRQFlags) {
-    // todo: replace this with local implementation, bios version is bugged
-    crate::syscall::intr_wait(1, flags.bits(), 0);
+    intr_wait(flags, true);
 }
 
 /// Waits for the VBlank interrupt, then continues.
@@ -170,6 +469,5 @@ pub fn wait_for_interrupt(flags: IRQFlags) {
 /// Equivalent to `wait_for_interrupt(IRQFlags::VBLANK)`.  
 /// Make sure interrupts are enabled before calling this!
 pub fn wait_for_vblank() {
-    // todo: replace this with local implementation, bios version is bugged
-    crate::syscall::vblank_intr_wait();
+    intr_wait(IRQFlags::VBLANK, true);
 }
@@ -0,0 +1,268 @@
+use crate::interrupt::{critical_section, intr_wait, irq_enable, IRQFlags};
+use crate::mmio;
+use crate::sync::{NdsCell, NdsCellSafe, NdsMutex};
+use core::ptr::{read_volatile, write_volatile};
+
+// https://www.problemkaputt.de/gbatek.htm#dsinterprocesscommunicationipc
+const FIFO_SEND_FULL: u16 = 1 << 1;
+const FIFO_SEND_EMPTY_IRQ: u16 = 1 << 2;
+const FIFO_SEND_CLEAR: u16 = 1 << 3;
+const FIFO_RECV_EMPTY: u16 = 1 << 8;
+const FIFO_RECV_NOT_EMPTY_IRQ: u16 = 1 << 10;
+const FIFO_ERROR: u16 = 1 << 14;
+const FIFO_ENABLE: u16 = 1 << 15;
+
+// Layout of the first word of every message:
+// bits 0-25  = payload (value, or address offset from the start of main RAM)
+// bit 26     = extended (payload didn't fit, the full 32 bit payload is in the next word)
+// bit 27     = address message
+// bits 28-31 = channel number
+const PAYLOAD_MASK: u32 = (1 << 26) - 1;
+const HEADER_EXTENDED: u32 = 1 << 26;
+const HEADER_ADDRESS: u32 = 1 << 27;
+const CHANNEL_SHIFT: u32 = 28;
+
+const MAIN_RAM_START: usize = 0x02000000;
+const MAIN_RAM_END: usize = 0x02400000;
+
+/// The number of message channels available.
+pub const NUM_CHANNELS: usize = 16;
+/// How many received messages can be waiting on each channel before new ones get dropped.
+pub const RECV_QUEUE_LEN: usize = 16;
+/// How many words can be waiting to be sent when the hardware FIFO is full.
+pub const SEND_OVERFLOW_LEN: usize = 64;
+
+/// A message sent between the ARM9 and the ARM7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// A 32 bit value. Values below `2^26` only take up one word in the FIFO, others take two.
+    Value(u32),
+    /// An address in memory. Addresses in main RAM only take up one word in the FIFO, others take two.
+    ///
+    /// Remember that the ARM9 data cache isn't visible to the ARM7, so make sure it is flushed
+    /// before sending the address of cached data.
+    Address(*mut u8),
+}
+
+/// The error returned by [`try_send`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The hardware FIFO and the software overflow queue are both full.
+    QueueFull,
+}
+
+/// Fixed size FIFO queue, used for the channel receive queues and the send overflow.
+struct RingBuffer<T: Copy, const N: usize> {
+    buf: [T; N],
+    start: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    const fn new(fill: T) -> Self {
+        Self { buf: [fill; N], start: 0, len: 0 }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// Adds a value to the back of the queue. Returns `false` if the queue is full.
+    fn push(&mut self, val: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.start + self.len) % N] = val;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let val = self.buf[self.start];
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(val)
+    }
+}
+
+type RecvQueue = RingBuffer<Message, RECV_QUEUE_LEN>;
+const EMPTY_RECV_QUEUE: RecvQueue = RingBuffer::new(Message::Value(0));
+static RECV_QUEUES: NdsMutex<[RecvQueue; NUM_CHANNELS]> = NdsMutex::new([EMPTY_RECV_QUEUE; NUM_CHANNELS]);
+
+// Words that didn't fit in the hardware FIFO. Sent by the "send FIFO empty" interrupt.
+static SEND_OVERFLOW: NdsMutex<RingBuffer<u32, SEND_OVERFLOW_LEN>> = NdsMutex::new(RingBuffer::new(0));
+
+type ChannelHandler = Option<fn(Message)>;
+unsafe impl NdsCellSafe for ChannelHandler {}
+static CHANNEL_HANDLERS: [NdsCell<ChannelHandler>; NUM_CHANNELS] = [const { NdsCell::new(None) }; NUM_CHANNELS];
+
+// First word of an extended message, if the second word hasn't arrived yet. 0 = None.
+// (the first word of an extended message always has HEADER_EXTENDED set, so it can't be 0)
+static PENDING_HEADER: NdsCell<u32> = NdsCell::new(0);
+
+#[inline(always)]
+fn read_fifo_cnt() -> u16 {
+    unsafe { read_volatile(mmio::IPCFIFOCNT as *mut u16) }
+}
+
+#[inline(always)]
+fn write_fifo_cnt(val: u16) {
+    unsafe { write_volatile(mmio::IPCFIFOCNT as *mut u16, val); }
+}
+
+/// Enables the FIFOs and the receive interrupt. Called in `lib_init`, before interrupts are enabled.
+pub(crate) fn fifo_init() {
+    write_fifo_cnt(FIFO_ENABLE | FIFO_ERROR | FIFO_RECV_NOT_EMPTY_IRQ | FIFO_SEND_CLEAR);
+    irq_enable(IRQFlags::IPC_RECV_FIFO_NOT_EMPTY | IRQFlags::IPC_SEND_FIFO_EMPTY);
+}
+
+fn encode(channel: u8, msg: Message) -> ([u32; 2], usize) {
+    debug_assert!((channel as usize) < NUM_CHANNELS, "IPC channel must be from 0 to 15 (was: {channel})");
+    let header = (channel as u32) << CHANNEL_SHIFT;
+    match msg {
+        Message::Value(v) if v <= PAYLOAD_MASK => ([header | v, 0], 1),
+        Message::Value(v) => ([header | HEADER_EXTENDED, v], 2),
+        Message::Address(a) if (MAIN_RAM_START..MAIN_RAM_END).contains(&(a as usize)) => {
+            ([header | HEADER_ADDRESS | (a as usize - MAIN_RAM_START) as u32, 0], 1)
+        }
+        Message::Address(a) => ([header | HEADER_ADDRESS | HEADER_EXTENDED, a as u32], 2),
+    }
+}
+
+/// Moves as many words as possible from the overflow queue into the hardware FIFO.
+fn flush_overflow(overflow: &mut RingBuffer<u32, SEND_OVERFLOW_LEN>) {
+    while !overflow.is_empty() && read_fifo_cnt() & FIFO_SEND_FULL == 0 {
+        if let Some(w) = overflow.pop() {
+            unsafe { write_volatile(mmio::IPCFIFOSEND as *mut u32, w); }
+        }
+    }
+    // only need the "send FIFO empty" interrupt while there's something left to send
+    if overflow.is_empty() {
+        write_fifo_cnt(read_fifo_cnt() & !FIFO_SEND_EMPTY_IRQ);
+    } else {
+        write_fifo_cnt(read_fifo_cnt() | FIFO_SEND_EMPTY_IRQ);
+    }
+}
+
+/// Sends a message to the other CPU, without waiting.
+///
+/// If the hardware FIFO is full, the message is put into a software overflow queue and
+/// will be sent from the interrupt handler when the FIFO empties.
+/// Returns [`SendError::QueueFull`] if there isn't room in the overflow queue either.
+pub fn try_send(channel: u8, msg: Message) -> Result<(), SendError> {
+    let (words, count) = encode(channel, msg);
+    let mut result = Ok(());
+    critical_section!({
+        let mut overflow = SEND_OVERFLOW.lock();
+        flush_overflow(&mut overflow);
+        if overflow.free() < count {
+            result = Err(SendError::QueueFull);
+        } else {
+            // both words of a message are queued together, so they can't be split up by
+            // another message sent from an interrupt
+            for &w in &words[..count] {
+                if overflow.is_empty() && read_fifo_cnt() & FIFO_SEND_FULL == 0 {
+                    unsafe { write_volatile(mmio::IPCFIFOSEND as *mut u32, w); }
+                } else {
+                    overflow.push(w);
+                }
+            }
+            flush_overflow(&mut overflow);
+        }
+    });
+    result
+}
+
+/// Sends a message to the other CPU.
+///
+/// If the hardware FIFO and the overflow queue are both full, this waits until there is space.
+pub fn send(channel: u8, msg: Message) {
+    while try_send(channel, msg).is_err() {}
+}
+
+/// Takes the oldest message out of a channel's receive queue, if there is one.
+#[must_use]
+pub fn try_receive(channel: u8) -> Option<Message> {
+    debug_assert!((channel as usize) < NUM_CHANNELS, "IPC channel must be from 0 to 15 (was: {channel})");
+    let msg;
+    critical_section!({
+        msg = RECV_QUEUES.lock()[channel as usize].pop();
+    });
+    msg
+}
+
+/// Waits until a message arrives on a channel, then returns it.
+///
+/// Make sure interrupts are enabled before calling this!
+/// This will never return if the channel has a handler set with [`set_channel_handler`],
+/// as those messages don't go into the receive queue.
+pub fn receive(channel: u8) -> Message {
+    loop {
+        if let Some(msg) = try_receive(channel) {
+            return msg;
+        }
+        // "return immediately if already set", in case the message arrived just after try_receive
+        intr_wait(IRQFlags::IPC_RECV_FIFO_NOT_EMPTY, false);
+    }
+}
+
+/// Sets a function that is called (from the interrupt handler) whenever a message arrives on a channel.
+///
+/// While a handler is set, messages on that channel don't go into the receive queue.
+/// Pass `None` to go back to queueing messages.
+pub fn set_channel_handler(channel: u8, handler: Option<fn(Message)>) {
+    debug_assert!((channel as usize) < NUM_CHANNELS, "IPC channel must be from 0 to 15 (was: {channel})");
+    CHANNEL_HANDLERS[channel as usize].write(handler);
+}
+
+fn dispatch(header: u32, payload: u32) {
+    let channel = (header >> CHANNEL_SHIFT) as usize;
+    let msg = if header & HEADER_ADDRESS == 0 {
+        Message::Value(payload)
+    } else if header & HEADER_EXTENDED == 0 {
+        Message::Address((MAIN_RAM_START + payload as usize) as *mut u8)
+    } else {
+        Message::Address(payload as *mut u8)
+    };
+
+    match CHANNEL_HANDLERS[channel].read() {
+        Some(f) => f(msg),
+        // if the queue is full, the message is dropped
+        None => { RECV_QUEUES.lock()[channel].push(msg); }
+    }
+}
+
+/// Handles the "send FIFO empty" interrupt, by sending the overflow queue.
+pub(crate) fn fifo_send_irq_handler() {
+    flush_overflow(&mut SEND_OVERFLOW.lock());
+}
+
+/// Handles the "receive FIFO not empty" interrupt, by reading and dispatching all the messages.
+pub(crate) fn fifo_recv_irq_handler() {
+    while read_fifo_cnt() & FIFO_RECV_EMPTY == 0 {
+        let word = unsafe { read_volatile(mmio::IPCFIFORECV as *mut u32) };
+        let pending = PENDING_HEADER.read();
+        if pending != 0 {
+            PENDING_HEADER.write(0);
+            dispatch(pending, word);
+        } else if word & HEADER_EXTENDED != 0 {
+            PENDING_HEADER.write(word);
+        } else {
+            dispatch(word, word & PAYLOAD_MASK);
+        }
+    }
+    // acknowledge the error flag, in case something read an empty FIFO or wrote to a full one
+    let cnt = read_fifo_cnt();
+    if cnt & FIFO_ERROR != 0 {
+        write_fifo_cnt(cnt);
+    }
+}
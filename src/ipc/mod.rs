@@ -0,0 +1,19 @@
+//! Module for communication between the ARM9 and the ARM7.
+//!
+//! See <https://www.problemkaputt.de/gbatek.htm#dsinterprocesscommunicationipc>
+
+mod fifo;
+mod signal;
+pub use fifo::*;
+pub use signal::*;
+
+use crate::interrupt::{irq_set_handler, IRQType};
+
+/// Sets up the FIFO and sync registers and their interrupt handlers. Called in `lib_init`, before interrupts are enabled.
+pub(crate) fn init() {
+    irq_set_handler(IRQType::IPCRecvFifoNotEmpty, fifo_recv_irq_handler);
+    irq_set_handler(IRQType::IPCSendFifoEmpty, fifo_send_irq_handler);
+    irq_set_handler(IRQType::IPCSync, sync_irq_handler);
+    fifo_init();
+    sync_init();
+}
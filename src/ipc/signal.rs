@@ -0,0 +1,144 @@
+use crate::interrupt::{irq_enable, IRQFlags};
+#[cfg(feature = "arm7")]
+use crate::interrupt::critical_section;
+use crate::sync::NdsCell;
+use crate::{mmio, shared};
+use core::ptr::{self, read_volatile, write_volatile};
+
+// https://www.problemkaputt.de/gbatek.htm#dsinterprocesscommunicationipc
+const SYNC_INPUT_MASK: u16 = 0xF;
+const SYNC_OUTPUT_SHIFT: u16 = 8;
+const SYNC_OUTPUT_MASK: u16 = 0xF << SYNC_OUTPUT_SHIFT;
+const SYNC_SEND_IRQ: u16 = 1 << 13;
+pub(crate) const SYNC_IRQ_ENABLE: u16 = 1 << 14;
+
+// Handshake steps. The ARM9 sets its sync value to each request in turn, and waits for the ARM7 to
+// answer with the matching reply. Using two steps means a sync value left over from whatever
+// ran before us can't be mistaken for a reply, and the last step puts both values back to 0.
+#[cfg(feature = "arm9")]
+const HANDSHAKE_STEPS: [(u8, u8); 3] = [(HANDSHAKE_REQ_1, HANDSHAKE_ACK_1), (HANDSHAKE_REQ_2, HANDSHAKE_ACK_2), (0, 0)];
+const HANDSHAKE_REQ_1: u8 = 0xC;
+const HANDSHAKE_ACK_1: u8 = 0xD;
+const HANDSHAKE_REQ_2: u8 = 0xE;
+const HANDSHAKE_ACK_2: u8 = 0xF;
+
+// ARM9: set when the ARM7 has answered the handshake
+// ARM7: set when the handshake has finished, so the ARM7 stops answering sync values
+static HANDSHAKE_DONE: NdsCell<bool> = NdsCell::new(false);
+
+#[inline(always)]
+fn read_sync_cnt() -> u16 {
+    unsafe { read_volatile(mmio::IPCSYNC as *mut u16) }
+}
+
+#[inline(always)]
+fn write_sync_cnt(val: u16) {
+    unsafe { write_volatile(mmio::IPCSYNC as *mut u16, val); }
+}
+
+/// Resets the sync value and enables the sync interrupt. Called in `lib_init`, before interrupts are enabled.
+pub(crate) fn sync_init() {
+    set_sync_value(0);
+    irq_enable(IRQFlags::IPC_SYNC);
+}
+
+/// Answers a handshake that the ARM9 started before the sync interrupt was enabled.
+/// Called at the end of `lib_init`, after interrupts are enabled.
+#[cfg(feature = "arm7")]
+pub(crate) fn sync_start() {
+    critical_section!({
+        answer_handshake();
+    });
+}
+
+/// Sets the 4-bit value that the other CPU sees with [`get_remote_sync_value`].
+///
+/// This doesn't trigger an interrupt on the other CPU, use [`send_sync`] for that.  
+/// Values 0xC to 0xF are used by the startup handshake (see `wait_for_arm7`). Until it has finished,
+/// the ARM7 answers 0xC and 0xE itself, so don't use them before then.
+#[inline]
+pub fn set_sync_value(value: u8) {
+    debug_assert!(value <= 0xF, "sync value must be from 0 to 15 (was: {value})");
+    let cnt = read_sync_cnt() & !(SYNC_OUTPUT_MASK | SYNC_SEND_IRQ);
+    write_sync_cnt(cnt | (((value as u16) << SYNC_OUTPUT_SHIFT) & SYNC_OUTPUT_MASK));
+}
+
+/// Gets the 4-bit value that this CPU is currently showing to the other CPU.
+#[must_use]
+#[inline]
+pub fn get_sync_value() -> u8 {
+    ((read_sync_cnt() & SYNC_OUTPUT_MASK) >> SYNC_OUTPUT_SHIFT) as u8
+}
+
+/// Gets the 4-bit value that the other CPU has set.
+#[must_use]
+#[inline]
+pub fn get_remote_sync_value() -> u8 {
+    (read_sync_cnt() & SYNC_INPUT_MASK) as u8
+}
+
+/// Triggers the `IPC_SYNC` interrupt on the other CPU.
+///
+/// The interrupt only happens if the other CPU has it enabled.
+#[inline]
+pub fn send_sync_irq() {
+    write_sync_cnt(read_sync_cnt() | SYNC_SEND_IRQ);
+}
+
+/// Sets the 4-bit sync value, then triggers the `IPC_SYNC` interrupt on the other CPU.
+///
+/// Values 0xC to 0xF are used by the startup handshake, like with [`set_sync_value`].
+#[inline]
+pub fn send_sync(value: u8) {
+    set_sync_value(value);
+    send_sync_irq();
+}
+
+/// Waits until the ARM7 has finished its startup code, and is ready to receive commands.
+///
+/// The ARM7 only answers once its `lib_init` has finished and interrupts are enabled.
+/// This will overwrite the sync value on both CPUs (it is left at 0).
+/// Calling this again after the ARM7 has answered returns immediately.
+#[cfg(feature = "arm9")]
+pub fn wait_for_arm7() {
+    if HANDSHAKE_DONE.read() {
+        return;
+    }
+    for (request, reply) in HANDSHAKE_STEPS {
+        send_sync(request);
+        while get_remote_sync_value() != reply {}
+    }
+    HANDSHAKE_DONE.write(true);
+}
+
+/// Replies to the ARM9's handshake, if it is waiting on one.
+#[cfg(feature = "arm7")]
+fn answer_handshake() {
+    if HANDSHAKE_DONE.read() {
+        return;
+    }
+    match get_remote_sync_value() {
+        HANDSHAKE_REQ_1 => send_sync(HANDSHAKE_ACK_1),
+        HANDSHAKE_REQ_2 => send_sync(HANDSHAKE_ACK_2),
+        0 if get_sync_value() == HANDSHAKE_ACK_2 => {
+            send_sync(0);
+            HANDSHAKE_DONE.write(true);
+        }
+        _ => {}
+    }
+}
+
+/// Handles the IPC sync interrupt.
+pub(crate) fn sync_irq_handler() {
+    #[cfg(feature = "arm9")]
+    if unsafe { read_volatile(ptr::addr_of!(shared::SHARED_DATA.arm7_panicked)) } {
+        crate::show_arm7_panic();
+    }
+    #[cfg(feature = "arm7")]
+    {
+        if unsafe { read_volatile(ptr::addr_of!(shared::SHARED_DATA.arm9_panicked)) } {
+            crate::halt_forever();
+        }
+        answer_handshake();
+    }
+}
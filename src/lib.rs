@@ -16,25 +16,37 @@ compile_error!("Feature \"arm9\" and feature \"arm7\" cannot be enabled at the s
 compile_error!("Either feature \"arm9\" or \"arm7\" must be enabled");
 
 extern crate alloc;
+#[cfg(feature = "arm9")]
 use alloc::string::String;
 use core::fmt::Write;
+use core::ptr;
 
 #[global_allocator]
 #[cfg_attr(feature = "arm9", link_section = ".dtcm.alloc")]
 static mut ALLOCATOR: allocator::ACSLAlloc = allocator::ACSLAlloc::new();
 
 pub mod agbabi;
+pub mod alarm;
 pub mod allocator;
 #[cfg(feature = "arm9")]
+pub mod cache;
+#[cfg(feature = "arm9")]
 pub mod display;
+pub mod dma;
 pub mod input;
 pub mod interrupt;
+pub mod ipc;
+#[cfg(feature = "arm9")]
+pub mod math;
 pub mod mmio;
 pub mod nocash;
 pub mod runtime;
 pub mod shared;
+#[cfg(feature = "arm7")]
+pub mod spi;
 pub mod sync;
 pub mod syscall;
+pub mod time;
 pub mod timers;
 
 // Accessing variables from the linkerscript is weird.
@@ -76,23 +88,39 @@ extern "C" fn lib_init() {
         display::set_brightness(display::GfxEngine::SUB, 0);
     }
     unsafe { ALLOCATOR.init(heap_start(), heap_size()); }
+    #[cfg(feature = "arm7")]
+    input::touch_init();
 
     interrupt::irq_disable(interrupt::IRQFlags::all());
-    interrupt::irq_set_handler(None); // it should be None already, just making sure
+    ipc::init();
     interrupt::enable_ime();
+    // only answer the ARM9's handshake once everything is set up
+    #[cfg(feature = "arm7")]
+    ipc::sync_start();
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    interrupt::disable_ime();
+    #[cfg(feature = "arm9")]
+    arm9_panic(info);
+    #[cfg(feature = "arm7")]
+    arm7_panic(info);
+}
+
+#[cfg(feature = "arm9")]
+fn arm9_panic(info: &core::panic::PanicInfo) -> ! {
     // concat! doesn't like const strings, this works as a workaround
     macro_rules! ERR_HEADER { () => { "      ---- ARM9 PANIC ----\n\n" }; }
     // todo: I don't think there's checking on that insert_str. also should move this string to the stack?
 
-    interrupt::disable_ime();
+    // stop the ARM7 (it halts when it sees this flag in the sync IRQ)
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.arm9_panicked), true); }
+    ipc::send_sync_irq();
+
     let mut output: String = String::new();
-    // todo: the underscore here silences warnings about this variable being unused on arm7
     // Reserve enough chars to fill the screen
-    let _printed_output = if output.try_reserve_exact(32 * 24).is_err() {
+    let printed_output = if output.try_reserve_exact(32 * 24).is_err() {
         concat!(ERR_HEADER!(), "Allocation failed: Out of memory")
     } else {
         match write!(&mut output, "{info}") {
@@ -100,12 +128,68 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
             Err(_) => concat!(ERR_HEADER!(), "Error formatting panic message.\nHow did this happen?"),
         }
     };
-    #[cfg(feature = "arm9")]
-    {
-        display::console::init_default();
-        display::console::print(_printed_output);
-        // arm9 panic should send message to halt arm7?
+    display::console::init_default();
+    display::console::print(printed_output);
+    loop { syscall::halt(); }
+}
+
+/// Shows the message written by the ARM7 panic handler. Called from the sync IRQ.
+#[cfg(feature = "arm9")]
+fn show_arm7_panic() -> ! {
+    interrupt::disable_ime();
+    let len = unsafe { ptr::read_volatile(ptr::addr_of!(shared::SHARED_DATA.arm7_panic_len)) };
+    let msg = unsafe { &*ptr::addr_of!(shared::SHARED_DATA.arm7_panic_msg) };
+    display::console::init_default();
+    display::console::print("      ---- ARM7 PANIC ----\n\n");
+    display::console::print(core::str::from_utf8(&msg[..len.min(shared::PANIC_MSG_LEN)])
+        .unwrap_or("Error reading panic message."));
+    loop { syscall::halt(); }
+}
+
+/// Writes text into a fixed size buffer, cutting it off when the buffer is full.
+#[cfg(feature = "arm7")]
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+#[cfg(feature = "arm7")]
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        // don't split a multi-byte character, so the message stays valid UTF-8
+        while !s.is_char_boundary(n) { n -= 1; }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "arm7")]
+fn arm7_panic(info: &core::panic::PanicInfo) -> ! {
+    // Formats directly into the shared region, so it works even if the heap is broken
+    let mut writer = TruncatingWriter {
+        buf: unsafe { &mut *ptr::addr_of_mut!(shared::SHARED_DATA.arm7_panic_msg) },
+        len: 0,
+    };
+    if write!(&mut writer, "{info}").is_err() {
+        writer.len = 0;
+        let _ = writer.write_str("Error formatting panic message.\nHow did this happen?");
     }
-    // todo: arm7 panic should send message to arm9 to display message
-    loop {}
+    unsafe {
+        ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.arm7_panic_len), writer.len);
+        ptr::write_volatile(ptr::addr_of_mut!(shared::SHARED_DATA.arm7_panicked), true);
+    }
+    // tell the ARM9 to display the message
+    ipc::send_sync_irq();
+    halt_forever();
+}
+
+/// Stops the ARM7 for good. Used when either CPU panics.
+#[cfg(feature = "arm7")]
+fn halt_forever() -> ! {
+    interrupt::disable_ime();
+    interrupt::irq_disable(interrupt::IRQFlags::all());
+    // with no interrupts enabled, halt never returns
+    loop { syscall::halt(); }
 }
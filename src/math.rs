@@ -0,0 +1,242 @@
+//! Module for using the ARM9 hardware divider and square root units.
+//!
+//! Every function here saves the state of the unit it uses, and puts it back when it's done.
+//! So they can be used from interrupt handlers, even if the interrupted code was in the middle of using the same unit.
+//! See <https://www.problemkaputt.de/gbatek.htm#dsmaths>
+
+use crate::mmio;
+use core::ptr::{read_volatile, write_volatile};
+use fixed::types::extra::LeEqU32;
+use fixed::types::I20F12;
+use fixed::{FixedI32, FixedU32};
+
+const DIV_MODE_32_32: u16 = 0;
+const DIV_MODE_64_32: u16 = 1;
+const DIV_MODE_64_64: u16 = 2;
+const DIV_MODE_MASK: u16 = 3;
+const SQRT_MODE_32: u16 = 0;
+const SQRT_MODE_64: u16 = 1;
+const SQRT_MODE_MASK: u16 = 1;
+const BUSY: u16 = 1 << 15;
+
+#[inline(always)]
+fn wait_div() {
+    while unsafe { read_volatile(mmio::DIVCNT as *mut u16) } & BUSY != 0 {}
+}
+
+#[inline(always)]
+fn wait_sqrt() {
+    while unsafe { read_volatile(mmio::SQRTCNT as *mut u16) } & BUSY != 0 {}
+}
+
+/// Runs a calculation on the divider, putting the old inputs back afterwards.
+///
+/// Writing the old inputs back starts the old calculation again, so code that was interrupted
+/// while using the divider still gets the right result. This waits for that calculation to finish,
+/// in case the interrupted code was part way through reading the result.
+#[inline(always)]
+fn with_divider<R>(mode: u16, numer: u64, denom: u64, read: impl FnOnce() -> R) -> R {
+    unsafe {
+        let old_mode = read_volatile(mmio::DIVCNT as *mut u16) & DIV_MODE_MASK;
+        let old_numer = read_volatile(mmio::DIV_NUMER as *mut u64);
+        let old_denom = read_volatile(mmio::DIV_DENOM as *mut u64);
+
+        write_volatile(mmio::DIVCNT as *mut u16, mode);
+        write_volatile(mmio::DIV_NUMER as *mut u64, numer);
+        write_volatile(mmio::DIV_DENOM as *mut u64, denom);
+        wait_div();
+        let result = read();
+
+        write_volatile(mmio::DIVCNT as *mut u16, old_mode);
+        write_volatile(mmio::DIV_NUMER as *mut u64, old_numer);
+        write_volatile(mmio::DIV_DENOM as *mut u64, old_denom);
+        wait_div();
+        result
+    }
+}
+
+/// Runs a calculation on the square root unit, putting the old input back afterwards.
+///
+/// Works the same way as [`with_divider`].
+#[inline(always)]
+fn with_sqrt(mode: u16, param: u64) -> u32 {
+    unsafe {
+        let old_mode = read_volatile(mmio::SQRTCNT as *mut u16) & SQRT_MODE_MASK;
+        let old_param = read_volatile(mmio::SQRT_PARAM as *mut u64);
+
+        write_volatile(mmio::SQRTCNT as *mut u16, mode);
+        write_volatile(mmio::SQRT_PARAM as *mut u64, param);
+        wait_sqrt();
+        let result = read_volatile(mmio::SQRT_RESULT as *mut u32);
+
+        write_volatile(mmio::SQRTCNT as *mut u16, old_mode);
+        write_volatile(mmio::SQRT_PARAM as *mut u64, old_param);
+        wait_sqrt();
+        result
+    }
+}
+
+#[inline(always)]
+fn read_quotient() -> u64 {
+    unsafe { read_volatile(mmio::DIV_RESULT as *mut u64) }
+}
+
+#[inline(always)]
+fn read_remainder() -> u64 {
+    unsafe { read_volatile(mmio::DIVREM_RESULT as *mut u64) }
+}
+
+#[inline(always)]
+fn read_both() -> (u64, u64) {
+    (read_quotient(), read_remainder())
+}
+
+/// Divides a 32 bit number by a 32 bit number, returning the quotient and remainder.
+///
+/// Like the `/` and `%` operators, the quotient is rounded towards zero.
+#[must_use]
+pub fn divrem32(num: i32, den: i32) -> (i32, i32) {
+    debug_assert!(den != 0, "attempt to divide by zero");
+    let (q, r) = with_divider(DIV_MODE_32_32, num as u64, den as u64, read_both);
+    (q as i32, r as i32)
+}
+
+/// Divides a 32 bit number by a 32 bit number.
+#[must_use]
+pub fn div32(num: i32, den: i32) -> i32 {
+    debug_assert!(den != 0, "attempt to divide by zero");
+    with_divider(DIV_MODE_32_32, num as u64, den as u64, read_quotient) as i32
+}
+
+/// Gets the remainder of dividing a 32 bit number by a 32 bit number.
+#[must_use]
+pub fn rem32(num: i32, den: i32) -> i32 {
+    debug_assert!(den != 0, "attempt to calculate the remainder with a divisor of zero");
+    with_divider(DIV_MODE_32_32, num as u64, den as u64, read_remainder) as i32
+}
+
+/// Divides a 64 bit number by a 32 bit number, returning the quotient and remainder.
+#[must_use]
+pub fn divrem64_32(num: i64, den: i32) -> (i64, i32) {
+    debug_assert!(den != 0, "attempt to divide by zero");
+    let (q, r) = with_divider(DIV_MODE_64_32, num as u64, den as u64, read_both);
+    (q as i64, r as i32)
+}
+
+/// Divides a 64 bit number by a 32 bit number.
+#[must_use]
+pub fn div64_32(num: i64, den: i32) -> i64 {
+    debug_assert!(den != 0, "attempt to divide by zero");
+    with_divider(DIV_MODE_64_32, num as u64, den as u64, read_quotient) as i64
+}
+
+/// Gets the remainder of dividing a 64 bit number by a 32 bit number.
+#[must_use]
+pub fn rem64_32(num: i64, den: i32) -> i32 {
+    debug_assert!(den != 0, "attempt to calculate the remainder with a divisor of zero");
+    with_divider(DIV_MODE_64_32, num as u64, den as u64, read_remainder) as i32
+}
+
+/// Divides a 64 bit number by a 64 bit number, returning the quotient and remainder.
+#[must_use]
+pub fn divrem64(num: i64, den: i64) -> (i64, i64) {
+    debug_assert!(den != 0, "attempt to divide by zero");
+    let (q, r) = with_divider(DIV_MODE_64_64, num as u64, den as u64, read_both);
+    (q as i64, r as i64)
+}
+
+/// Divides a 64 bit number by a 64 bit number.
+#[must_use]
+pub fn div64(num: i64, den: i64) -> i64 {
+    debug_assert!(den != 0, "attempt to divide by zero");
+    with_divider(DIV_MODE_64_64, num as u64, den as u64, read_quotient) as i64
+}
+
+/// Gets the remainder of dividing a 64 bit number by a 64 bit number.
+#[must_use]
+pub fn rem64(num: i64, den: i64) -> i64 {
+    debug_assert!(den != 0, "attempt to calculate the remainder with a divisor of zero");
+    with_divider(DIV_MODE_64_64, num as u64, den as u64, read_remainder) as i64
+}
+
+/// Gets the square root of a 32 bit number, rounded down.
+#[must_use]
+pub fn sqrt32(x: u32) -> u16 {
+    with_sqrt(SQRT_MODE_32, x as u64) as u16
+}
+
+/// Gets the square root of a 64 bit number, rounded down.
+#[must_use]
+pub fn sqrt64(x: u64) -> u32 {
+    with_sqrt(SQRT_MODE_64, x)
+}
+
+/// Divides two 32 bit fixed point numbers (like [`I20F12`]), using the hardware divider.
+///
+/// The result is rounded towards zero. If the result doesn't fit, it wraps around.
+#[must_use]
+pub fn div_fixed<Frac: LeEqU32>(num: FixedI32<Frac>, den: FixedI32<Frac>) -> FixedI32<Frac> {
+    // shift the numerator up, so the fraction bits cancel out
+    let num = (num.to_bits() as i64) << FixedI32::<Frac>::FRAC_NBITS;
+    FixedI32::from_bits(div64_32(num, den.to_bits()) as i32)
+}
+
+/// Divides two unsigned 32 bit fixed point numbers, using the hardware divider.
+///
+/// The result is rounded down. If the result doesn't fit, it wraps around.
+#[must_use]
+pub fn div_ufixed<Frac: LeEqU32>(num: FixedU32<Frac>, den: FixedU32<Frac>) -> FixedU32<Frac> {
+    let num = (num.to_bits() as u64) << FixedU32::<Frac>::FRAC_NBITS;
+    debug_assert!(num <= i64::MAX as u64, "fixed point numerator too large for the hardware divider");
+    // both values are positive, so the 64/64 mode is needed for denominators with the top bit set
+    FixedU32::from_bits(div64(num as i64, den.to_bits() as i64) as u32)
+}
+
+/// Gets the square root of a 32 bit fixed point number, rounded down.
+///
+/// `x` must not be negative.
+#[must_use]
+pub fn sqrt_fixed<Frac: LeEqU32>(x: FixedI32<Frac>) -> FixedI32<Frac> {
+    debug_assert!(x >= 0, "can't get the square root of a negative number (was: {x})");
+    // sqrt(x * 2^f) = sqrt(x) * 2^(f/2), so shift by f to get f fraction bits out
+    let bits = (x.to_bits() as u64) << FixedI32::<Frac>::FRAC_NBITS;
+    FixedI32::from_bits(sqrt64(bits) as i32)
+}
+
+/// Gets the square root of an unsigned 32 bit fixed point number, rounded down.
+#[must_use]
+pub fn sqrt_ufixed<Frac: LeEqU32>(x: FixedU32<Frac>) -> FixedU32<Frac> {
+    let bits = (x.to_bits() as u64) << FixedU32::<Frac>::FRAC_NBITS;
+    FixedU32::from_bits(sqrt64(bits))
+}
+
+/// Gets the sine of an angle, where a full turn is `0x10000` (so 90 degrees is `0x4000`).
+///
+/// Doesn't use the hardware units. Accurate to about 1/4096.
+#[must_use]
+pub fn sin(angle: u16) -> I20F12 {
+    // reduce to -90..90 degrees, where the sine is the same
+    let mut t = angle as i16 as i32;
+    if t > 0x4000 {
+        t = 0x8000 - t;
+    } else if t < -0x4000 {
+        t = -0x8000 - t;
+    }
+    // angle in radians, with 16 fraction bits (2 pi * 2^16 = 411775)
+    let x = (t as i64 * 411775) >> 16;
+    let x2 = (x * x) >> 16;
+    // Taylor series up to x^7: x * (1 - x^2/6 * (1 - x^2/20 * (1 - x^2/42)))
+    let mut r = (1 << 16) - x2 / 42;
+    r = (1 << 16) - ((x2 * r) >> 16) / 20;
+    r = (1 << 16) - ((x2 * r) >> 16) / 6;
+    // round from 16 fraction bits to 12
+    I20F12::from_bits(((((x * r) >> 16) + (1 << 3)) >> 4) as i32)
+}
+
+/// Gets the cosine of an angle, where a full turn is `0x10000` (so 90 degrees is `0x4000`).
+///
+/// Doesn't use the hardware units. Accurate to about 1/4096.
+#[must_use]
+pub fn cos(angle: u16) -> I20F12 {
+    sin(angle.wrapping_add(0x4000))
+}
@@ -68,15 +68,83 @@ def_mmio!(0x0400_1012 = BG0YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub B
 def_mmio!(0x0400_0014 = BG1XOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 1 X Offset");
 def_mmio!(0x0400_1014 = BG1XOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 1 X Offset");
 def_mmio!(0x0400_0016 = BG1YOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 1 Y Offset");
-def_mmio!(0x0400_1016 = BG2YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 1 Y Offset");
+def_mmio!(0x0400_1016 = BG1YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 1 Y Offset");
 def_mmio!(0x0400_0018 = BG2XOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 2 X Offset");
 def_mmio!(0x0400_1018 = BG2XOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 2 X Offset");
-def_mmio!(0x0400_001A = BG20YOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 2 Y Offset");
-def_mmio!(0x0400_101A = BG20YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 2 Y Offset");
-def_mmio!(0x0400_001C = BG30XOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 3 X Offset");
-def_mmio!(0x0400_101C = BG30XOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 3 X Offset");
-def_mmio!(0x0400_001E = BG30YOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 3 Y Offset");
-def_mmio!(0x0400_101E = BG30YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 3 Y Offset");
+def_mmio!(0x0400_001A = BG2YOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 2 Y Offset");
+def_mmio!(0x0400_101A = BG2YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 2 Y Offset");
+def_mmio!(0x0400_001C = BG3XOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 3 X Offset");
+def_mmio!(0x0400_101C = BG3XOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 3 X Offset");
+def_mmio!(0x0400_001E = BG3YOFS_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Background 3 Y Offset");
+def_mmio!(0x0400_101E = BG3YOFS_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Background 3 Y Offset");
+
+// The old misspelt names, kept so existing code still builds
+#[cfg(feature = "arm9")]
+#[deprecated(note = "renamed to `BG2YOFS_MAIN`")]
+pub const BG20YOFS_MAIN: VolAddress<u16, (), Safe> = BG2YOFS_MAIN;
+#[cfg(feature = "arm9")]
+#[deprecated(note = "renamed to `BG2YOFS_SUB`")]
+pub const BG20YOFS_SUB: VolAddress<u16, (), Safe> = BG2YOFS_SUB;
+#[cfg(feature = "arm9")]
+#[deprecated(note = "renamed to `BG3XOFS_MAIN`")]
+pub const BG30XOFS_MAIN: VolAddress<u16, (), Safe> = BG3XOFS_MAIN;
+#[cfg(feature = "arm9")]
+#[deprecated(note = "renamed to `BG3XOFS_SUB`")]
+pub const BG30XOFS_SUB: VolAddress<u16, (), Safe> = BG3XOFS_SUB;
+#[cfg(feature = "arm9")]
+#[deprecated(note = "renamed to `BG3YOFS_MAIN`")]
+pub const BG30YOFS_MAIN: VolAddress<u16, (), Safe> = BG3YOFS_MAIN;
+#[cfg(feature = "arm9")]
+#[deprecated(note = "renamed to `BG3YOFS_SUB`")]
+pub const BG30YOFS_SUB: VolAddress<u16, (), Safe> = BG3YOFS_SUB;
+
+def_mmio!(0x0400_0020 = BG2PA_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 2 Affine Parameter A");
+def_mmio!(0x0400_1020 = BG2PA_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 2 Affine Parameter A");
+def_mmio!(0x0400_0022 = BG2PB_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 2 Affine Parameter B");
+def_mmio!(0x0400_1022 = BG2PB_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 2 Affine Parameter B");
+def_mmio!(0x0400_0024 = BG2PC_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 2 Affine Parameter C");
+def_mmio!(0x0400_1024 = BG2PC_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 2 Affine Parameter C");
+def_mmio!(0x0400_0026 = BG2PD_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 2 Affine Parameter D");
+def_mmio!(0x0400_1026 = BG2PD_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 2 Affine Parameter D");
+def_mmio!(0x0400_0028 = BG2X_MAIN: VolAddress<i32, (), Safe>; ["arm9"]; "Main Background 2 Reference Point X");
+def_mmio!(0x0400_1028 = BG2X_SUB: VolAddress<i32, (), Safe>; ["arm9"]; "Sub Background 2 Reference Point X");
+def_mmio!(0x0400_002C = BG2Y_MAIN: VolAddress<i32, (), Safe>; ["arm9"]; "Main Background 2 Reference Point Y");
+def_mmio!(0x0400_102C = BG2Y_SUB: VolAddress<i32, (), Safe>; ["arm9"]; "Sub Background 2 Reference Point Y");
+def_mmio!(0x0400_0030 = BG3PA_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 3 Affine Parameter A");
+def_mmio!(0x0400_1030 = BG3PA_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 3 Affine Parameter A");
+def_mmio!(0x0400_0032 = BG3PB_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 3 Affine Parameter B");
+def_mmio!(0x0400_1032 = BG3PB_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 3 Affine Parameter B");
+def_mmio!(0x0400_0034 = BG3PC_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 3 Affine Parameter C");
+def_mmio!(0x0400_1034 = BG3PC_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 3 Affine Parameter C");
+def_mmio!(0x0400_0036 = BG3PD_MAIN: VolAddress<i16, (), Safe>; ["arm9"]; "Main Background 3 Affine Parameter D");
+def_mmio!(0x0400_1036 = BG3PD_SUB: VolAddress<i16, (), Safe>; ["arm9"]; "Sub Background 3 Affine Parameter D");
+def_mmio!(0x0400_0038 = BG3X_MAIN: VolAddress<i32, (), Safe>; ["arm9"]; "Main Background 3 Reference Point X");
+def_mmio!(0x0400_1038 = BG3X_SUB: VolAddress<i32, (), Safe>; ["arm9"]; "Sub Background 3 Reference Point X");
+def_mmio!(0x0400_003C = BG3Y_MAIN: VolAddress<i32, (), Safe>; ["arm9"]; "Main Background 3 Reference Point Y");
+def_mmio!(0x0400_103C = BG3Y_SUB: VolAddress<i32, (), Safe>; ["arm9"]; "Sub Background 3 Reference Point Y");
+
+// https://www.problemkaputt.de/gbatek.htm#lcdiowindowfeature
+// https://www.problemkaputt.de/gbatek.htm#lcdiocolorspecialeffects
+def_mmio!(0x0400_0040 = WIN0H_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Window 0 Horizontal Dimensions");
+def_mmio!(0x0400_1040 = WIN0H_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Window 0 Horizontal Dimensions");
+def_mmio!(0x0400_0042 = WIN1H_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Window 1 Horizontal Dimensions");
+def_mmio!(0x0400_1042 = WIN1H_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Window 1 Horizontal Dimensions");
+def_mmio!(0x0400_0044 = WIN0V_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Window 0 Vertical Dimensions");
+def_mmio!(0x0400_1044 = WIN0V_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Window 0 Vertical Dimensions");
+def_mmio!(0x0400_0046 = WIN1V_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Window 1 Vertical Dimensions");
+def_mmio!(0x0400_1046 = WIN1V_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Window 1 Vertical Dimensions");
+def_mmio!(0x0400_0048 = WININ_MAIN: VolAddress<u16, Safe, Safe>; ["arm9"]; "Main Inside of Window 0 and 1");
+def_mmio!(0x0400_1048 = WININ_SUB: VolAddress<u16, Safe, Safe>; ["arm9"]; "Sub Inside of Window 0 and 1");
+def_mmio!(0x0400_004A = WINOUT_MAIN: VolAddress<u16, Safe, Safe>; ["arm9"]; "Main Inside of OBJ Window & Outside of Windows");
+def_mmio!(0x0400_104A = WINOUT_SUB: VolAddress<u16, Safe, Safe>; ["arm9"]; "Sub Inside of OBJ Window & Outside of Windows");
+def_mmio!(0x0400_004C = MOSAIC_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Mosaic Size");
+def_mmio!(0x0400_104C = MOSAIC_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Mosaic Size");
+def_mmio!(0x0400_0050 = BLDCNT_MAIN: VolAddress<u16, Safe, Safe>; ["arm9"]; "Main Color Special Effects Selection");
+def_mmio!(0x0400_1050 = BLDCNT_SUB: VolAddress<u16, Safe, Safe>; ["arm9"]; "Sub Color Special Effects Selection");
+def_mmio!(0x0400_0052 = BLDALPHA_MAIN: VolAddress<u16, Safe, Safe>; ["arm9"]; "Main Alpha Blending Coefficients");
+def_mmio!(0x0400_1052 = BLDALPHA_SUB: VolAddress<u16, Safe, Safe>; ["arm9"]; "Sub Alpha Blending Coefficients");
+def_mmio!(0x0400_0054 = BLDY_MAIN: VolAddress<u16, (), Safe>; ["arm9"]; "Main Brightness (Fade-In/Out) Coefficient");
+def_mmio!(0x0400_1054 = BLDY_SUB: VolAddress<u16, (), Safe>; ["arm9"]; "Sub Brightness (Fade-In/Out) Coefficient");
 
 // arm9 and arm7 have their own separate DISPSTATS
 def_mmio!(0x0400_0004 = DISPSTAT: VolAddress<u16, Safe, Safe>; ["arm9", "arm7"]; "Display Status");
@@ -1,12 +1,32 @@
 //! Handles the shared memory region between the ARM9 and the ARM7.
 
-use crate::input::Buttons;
+use crate::input::{Buttons, TouchPosition};
+
+/// Maximum length of the ARM7 panic message (enough to fill the screen).
+pub const PANIC_MSG_LEN: usize = 32 * 24;
 
 #[link_section = ".shared"]
 pub static mut SHARED_DATA: SharedData = SharedData {
     buttons: Buttons::empty(),
+    touch_seq: 0,
+    touch_pressed: false,
+    touch: TouchPosition::new(),
+    arm9_panicked: false,
+    arm7_panicked: false,
+    arm7_panic_len: 0,
+    arm7_panic_msg: [0; PANIC_MSG_LEN],
 };
 
 pub struct SharedData {
     pub buttons: Buttons,
+    // written by the ARM7 in scan_touch, read by the ARM9 in read_touch
+    pub(crate) touch_seq: u32,
+    pub(crate) touch_pressed: bool,
+    pub(crate) touch: TouchPosition,
+    // set by a CPU when it panics, before sending a sync IRQ to the other one
+    pub(crate) arm9_panicked: bool,
+    pub(crate) arm7_panicked: bool,
+    // UTF-8 message written by the ARM7 panic handler, for the ARM9 to display
+    pub(crate) arm7_panic_len: usize,
+    pub(crate) arm7_panic_msg: [u8; PANIC_MSG_LEN],
 }
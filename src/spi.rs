@@ -0,0 +1,69 @@
+//! Module for talking to devices on the SPI bus (power manager, firmware and touchscreen).
+//!
+//! Only usable on ARM7.
+//! See <https://www.problemkaputt.de/gbatek.htm#dsserialperipheralinterfacebusspi>
+
+use crate::interrupt::critical_section;
+use crate::mmio;
+use core::ptr::{read_volatile, write_volatile};
+
+const SPI_BUSY: u16 = 1 << 7;
+const SPI_HOLD: u16 = 1 << 11;
+const SPI_ENABLE: u16 = 1 << 15;
+
+const FIRMWARE_READ: u8 = 0x03;
+
+/// A device on the SPI bus, selected for each transfer.
+#[derive(Clone, Copy)]
+pub enum SpiDevice {
+    PowerManager = 0 << 8,
+    Firmware = 1 << 8,
+    Touchscreen = 2 << 8,
+}
+
+/// The clock speed of an SPI transfer.
+#[derive(Clone, Copy)]
+pub enum SpiBaudrate {
+    Baud4MHz = 0,
+    Baud2MHz = 1,
+    Baud1MHz = 2,
+    Baud512KHz = 3,
+}
+
+/// Waits until the current SPI transfer has finished.
+#[inline]
+pub fn wait_busy() {
+    while unsafe { read_volatile(mmio::SPICNT as *mut u16) } & SPI_BUSY != 0 {}
+}
+
+/// Selects a device and sends one byte to it, returning the byte received at the same time.
+///
+/// If `hold` is true, the device stays selected after the transfer, so the next transfer
+/// continues the same command. The last byte of a command should be sent with `hold` set to false.
+/// This isn't interrupt-safe by itself, so a whole command should be done inside a critical section.
+pub fn transfer(device: SpiDevice, baudrate: SpiBaudrate, hold: bool, data: u8) -> u8 {
+    let hold = if hold { SPI_HOLD } else { 0 };
+    wait_busy();
+    unsafe {
+        write_volatile(mmio::SPICNT as *mut u16, SPI_ENABLE | hold | device as u16 | baudrate as u16);
+        write_volatile(mmio::SPIDATA as *mut u16, data as u16);
+    }
+    wait_busy();
+    unsafe { read_volatile(mmio::SPIDATA as *mut u16) as u8 }
+}
+
+/// Reads bytes out of the firmware flash memory, starting at `addr`.
+pub fn read_firmware(addr: u32, buf: &mut [u8]) {
+    critical_section!({
+        let cmd = [FIRMWARE_READ, (addr >> 16) as u8, (addr >> 8) as u8, addr as u8];
+        for b in cmd {
+            transfer(SpiDevice::Firmware, SpiBaudrate::Baud4MHz, true, b);
+        }
+        for b in buf.iter_mut() {
+            *b = transfer(SpiDevice::Firmware, SpiBaudrate::Baud4MHz, true, 0);
+        }
+        // deselect the firmware chip
+        wait_busy();
+        unsafe { write_volatile(mmio::SPICNT as *mut u16, 0); }
+    });
+}
@@ -0,0 +1,246 @@
+//! Module for measuring time, using a pair of hardware timers as a 64 bit clock.
+//!
+//! Call [`init`] once to reserve the timers and start the clock. The clock counts at the
+//! bus clock speed ([`TIMER_FREQ`], about 33.514 MHz), so it won't overflow for over 17000 years.
+
+use crate::interrupt::{critical_section, intr_wait, irq_disable, irq_enable, irq_is_pending, IRQFlags};
+use crate::sync::NdsCell;
+use crate::timers::{Prescaler, Timer, BASE_TIMER_ADDR, TIMER_FREQ};
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::ptr::read_volatile;
+use core::time::Duration;
+
+const NOT_INITIALISED: u8 = 0xFF;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// Ticks between overflows of the low timer, which is what wakes up `sleep`
+const LOW_TIMER_PERIOD: u64 = 0x10000;
+
+// Index of the low timer of the pair, or NOT_INITIALISED
+static CLOCK_TIMER: NdsCell<u8> = NdsCell::new(NOT_INITIALISED);
+// Bits 32-63 of the tick count, incremented when the high timer overflows
+static CLOCK_HIGH: NdsCell<u32> = NdsCell::new(0);
+
+/// Starts the clock, using hardware timers `timer_index` and `timer_index + 1`.
+///
+/// `timer_index` must be from 0 to 2. Those timers are reserved forever,
+/// and can't be taken with [`Timer::take`]. Calling this again does nothing.
+///
+/// # Panics
+/// Panics if either timer already has a [`Timer`] handle.
+pub fn init(timer_index: u32) {
+    debug_assert!(timer_index <= 2, "invalid timer index for time::init (must be 0 to 2)");
+    if CLOCK_TIMER.read() != NOT_INITIALISED {
+        return;
+    }
+    let mut low = Timer::take(timer_index).expect("timer used by time::init is already taken");
+    let mut high = Timer::take(timer_index + 1).expect("timer used by time::init is already taken");
+
+    CLOCK_HIGH.write(0);
+    high.set_overflow_handler(|| CLOCK_HIGH.write(CLOCK_HIGH.read().wrapping_add(1)));
+    // the low timer's interrupt is only enabled while sleeping
+    low.set_overflow_handler(|| {});
+    irq_disable(low.irq_flag());
+
+    high.start_cascade(0);
+    low.start(Prescaler::Div1, 0);
+    CLOCK_TIMER.write(timer_index as u8);
+    // the handles are never dropped, so the timers stay running and reserved
+    core::mem::forget(low);
+    core::mem::forget(high);
+}
+
+/// Checks if the clock has been started with [`init`].
+#[must_use]
+#[inline]
+pub fn is_initialised() -> bool {
+    CLOCK_TIMER.read() != NOT_INITIALISED
+}
+
+#[inline(always)]
+fn clock_timer() -> u32 {
+    let index = CLOCK_TIMER.read();
+    assert!(index != NOT_INITIALISED, "time::init must be called before using the clock");
+    index as u32
+}
+
+/// Gets the number of ticks since [`init`] was called. There are [`TIMER_FREQ`] ticks per second.
+#[must_use]
+pub fn ticks() -> u64 {
+    let index = clock_timer();
+    let low_addr = (BASE_TIMER_ADDR + (index * 4) as usize) as *mut u16;
+    let high_addr = (BASE_TIMER_ADDR + (index * 4) as usize + 4) as *mut u16;
+    let overflow_flag = IRQFlags::from_bits_retain(IRQFlags::TIMER0.bits() << (index + 1));
+    let result;
+    critical_section!({
+        result = loop {
+            unsafe {
+                // Interrupts are off, so an overflow of the high timer can be pending without
+                // CLOCK_HIGH being incremented. That includes when this is called from a higher priority
+                // handler in the same batch of interrupts as the overflow, after IF has been acknowledged.
+                // If the low timer overflows or the overflow happens in the middle of reading, try again.
+                let pending_before = irq_is_pending(overflow_flag);
+                let high_before = read_volatile(high_addr);
+                let low = read_volatile(low_addr);
+                let high_after = read_volatile(high_addr);
+                let pending_after = irq_is_pending(overflow_flag);
+                if high_before == high_after && pending_before == pending_after {
+                    let upper = CLOCK_HIGH.read().wrapping_add(pending_after as u32);
+                    break ((upper as u64) << 32) | ((high_after as u64) << 16) | low as u64;
+                }
+            }
+        };
+    });
+    result
+}
+
+/// Converts a number of clock ticks to a [`Duration`], rounded down to the nearest nanosecond.
+#[must_use]
+pub const fn ticks_to_duration(ticks: u64) -> Duration {
+    let secs = ticks / TIMER_FREQ as u64;
+    let nanos = (ticks % TIMER_FREQ as u64) * NANOS_PER_SEC / TIMER_FREQ as u64;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Converts a [`Duration`] to a number of clock ticks, rounded up, or returns `None` if it doesn't fit in a `u64`.
+#[must_use]
+pub const fn checked_duration_to_ticks(duration: Duration) -> Option<u64> {
+    // can't overflow, since subsec_nanos is less than NANOS_PER_SEC
+    let nanos = (duration.subsec_nanos() as u64 * TIMER_FREQ as u64).div_ceil(NANOS_PER_SEC);
+    match duration.as_secs().checked_mul(TIMER_FREQ as u64) {
+        Some(secs) => secs.checked_add(nanos),
+        None => None,
+    }
+}
+
+/// Converts a [`Duration`] to a number of clock ticks, rounded up. Saturates at `u64::MAX`.
+#[must_use]
+pub const fn duration_to_ticks(duration: Duration) -> u64 {
+    match checked_duration_to_ticks(duration) {
+        Some(ticks) => ticks,
+        None => u64::MAX,
+    }
+}
+
+/// A point in time, measured by the clock. Works like `std::time::Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Gets the current time.
+    #[must_use]
+    #[inline]
+    pub fn now() -> Self {
+        Self(ticks())
+    }
+
+    /// Gets the time from the number of ticks since [`init`] was called.
+    #[must_use]
+    #[inline]
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// The number of ticks since [`init`] was called.
+    #[must_use]
+    #[inline]
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Gets the time that has passed since this instant.
+    #[must_use]
+    #[inline]
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+
+    /// Gets the time from `earlier` to this instant, or zero if `earlier` is later than this.
+    #[must_use]
+    #[inline]
+    pub const fn duration_since(self, earlier: Self) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Adds a duration to this instant, returning `None` if it overflows.
+    #[must_use]
+    #[inline]
+    pub const fn checked_add(self, duration: Duration) -> Option<Self> {
+        match checked_duration_to_ticks(duration) {
+            Some(ticks) => match self.0.checked_add(ticks) {
+                Some(t) => Some(Self(t)),
+                None => None,
+            },
+            None => None,
+        }
+    }
+
+    /// Subtracts a duration from this instant, returning `None` if it would be before the clock started.
+    #[must_use]
+    #[inline]
+    pub const fn checked_sub(self, duration: Duration) -> Option<Self> {
+        match checked_duration_to_ticks(duration) {
+            Some(ticks) => match self.0.checked_sub(ticks) {
+                Some(t) => Some(Self(t)),
+                None => None,
+            },
+            None => None,
+        }
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+    fn add(self, rhs: Duration) -> Self {
+        self.checked_add(rhs).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Self;
+    fn sub(self, rhs: Duration) -> Self {
+        self.checked_sub(rhs).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Waits for `duration` by repeatedly checking the clock.
+///
+/// This is the most accurate way to wait, but keeps the CPU busy. Can be used in interrupt handlers.
+pub fn sleep_busy(duration: Duration) {
+    let end = ticks().saturating_add(duration_to_ticks(duration));
+    while ticks() < end {}
+}
+
+/// Waits for `duration`, halting the CPU most of the time to save power.
+///
+/// The CPU is woken up about every 2 milliseconds by the clock timer, and the last part
+/// is done with [`sleep_busy`] for accuracy. Other interrupts still run while sleeping.
+/// Make sure interrupts are enabled before calling this! Use [`sleep_busy`] in interrupt handlers instead.
+pub fn sleep(duration: Duration) {
+    let end = ticks().saturating_add(duration_to_ticks(duration));
+    let wake_flag = IRQFlags::from_bits_retain(IRQFlags::TIMER0.bits() << clock_timer());
+    irq_enable(wake_flag);
+    while end.saturating_sub(ticks()) > LOW_TIMER_PERIOD {
+        intr_wait(wake_flag, true);
+    }
+    irq_disable(wake_flag);
+    while ticks() < end {}
+}
@@ -1,24 +1,276 @@
 //! Module for utilising the hardware timers.
-#![allow(dead_code)] // for unused consts like PRESCALER_64
 
+use crate::interrupt::{critical_section, irq_disable, irq_enable, irq_remove_handler, irq_set_handler, IRQFlags, IRQType};
 use crate::mmio;
+use crate::sync::NdsCell;
 use core::ptr;
 
 // DS timers are the same as GBA, just incrementing at 33 MHz
 // https://problemkaputt.de/gbatek.htm#gbatimers
 
-const BASE_TIMER_ADDR: usize = mmio::TM0CNT_L;
-
-const PRESCALER_1: u16 = 0;
-const PRESCALER_64: u16 = 1;
-const PRESCALER_256: u16 = 2;
-const PRESCALER_1024: u16 = 3;
-const COUNT_UP_OFF: u16 = 0;
-const COUNT_UP_ON: u16 = 0x4;
-const IRQ_DISABLE: u16 = 0;
-const IRQ_ENABLE: u16 = 0x40;
-const TIMER_STOP: u16 = 0;
-const TIMER_START: u16 = 0x80;
+/// The frequency that the timers count at with a prescaler of 1 (the bus clock), in Hz.
+pub const TIMER_FREQ: u32 = 33_513_982;
+
+pub(crate) const BASE_TIMER_ADDR: usize = mmio::TM0CNT_L;
+
+// Timer control register (TMxCNT_H) values
+pub(crate) const PRESCALER_1: u16 = 0;
+pub(crate) const PRESCALER_64: u16 = 1;
+pub(crate) const PRESCALER_256: u16 = 2;
+pub(crate) const PRESCALER_1024: u16 = 3;
+pub(crate) const COUNT_UP_OFF: u16 = 0;
+pub(crate) const COUNT_UP_ON: u16 = 0x4;
+pub(crate) const IRQ_DISABLE: u16 = 0;
+pub(crate) const IRQ_ENABLE: u16 = 0x40;
+pub(crate) const TIMER_STOP: u16 = 0;
+pub(crate) const TIMER_START: u16 = 0x80;
+
+// Which hardware timers have a Timer handle
+static TIMERS_TAKEN: NdsCell<u8> = NdsCell::new(0);
+
+/// How many bus clock cycles each timer tick takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prescaler {
+    Div1 = PRESCALER_1 as isize,
+    Div64 = PRESCALER_64 as isize,
+    Div256 = PRESCALER_256 as isize,
+    Div1024 = PRESCALER_1024 as isize,
+}
+
+impl Prescaler {
+    /// The number of bus clock cycles per tick.
+    #[must_use]
+    #[inline]
+    pub const fn divisor(self) -> u32 {
+        match self {
+            Self::Div1 => 1,
+            Self::Div64 => 64,
+            Self::Div256 => 256,
+            Self::Div1024 => 1024,
+        }
+    }
+
+    /// The number of ticks per second, rounded down.
+    #[must_use]
+    #[inline]
+    pub const fn frequency(self) -> u32 {
+        TIMER_FREQ / self.divisor()
+    }
+}
+
+/// A handle to one of the 4 hardware timers.
+///
+/// Only one handle can exist for each timer at a time. Dropping the handle stops the timer,
+/// and removes its overflow handler.  
+/// Don't use a timer that has a handle with [`start_profiler_timer`].
+pub struct Timer {
+    index: u32,
+    // needed to put back after resume, which uses the reload register to restore the counter
+    reload: u16,
+    // TMxCNT_H value, without the start bit
+    control: u16,
+    paused: bool,
+}
+
+impl Timer {
+    /// Takes the handle for hardware timer `index` (0-3).
+    ///
+    /// Returns `None` if there is already a handle for that timer, or `index` isn't a valid timer.
+    #[must_use]
+    pub fn take(index: u32) -> Option<Self> {
+        if index > 3 {
+            return None;
+        }
+        let mut free = false;
+        critical_section!({
+            let taken = TIMERS_TAKEN.read();
+            if taken & (1 << index) == 0 {
+                TIMERS_TAKEN.write(taken | (1 << index));
+                free = true;
+            }
+        });
+        if free {
+            let mut timer = Self { index, reload: 0, control: 0, paused: false };
+            timer.stop();
+            Some(timer)
+        } else {
+            None
+        }
+    }
+
+    /// The index of this timer (0-3).
+    #[must_use]
+    #[inline]
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    #[inline(always)]
+    fn counter_addr(&self) -> *mut u16 {
+        (BASE_TIMER_ADDR + (self.index * 4) as usize) as *mut u16
+    }
+
+    #[inline(always)]
+    fn control_addr(&self) -> *mut u16 {
+        (BASE_TIMER_ADDR + (self.index * 4) as usize + 2) as *mut u16
+    }
+
+    #[inline(always)]
+    fn write_control(&self, running: bool) {
+        let start = if running { TIMER_START } else { TIMER_STOP };
+        unsafe { ptr::write_volatile(self.control_addr(), self.control | start); }
+    }
+
+    /// Starts the timer, counting up from `reload` at the speed set by `prescaler`.
+    ///
+    /// When the counter overflows, it goes back to `reload`, and the overflow handler runs.
+    pub fn start(&mut self, prescaler: Prescaler, reload: u16) {
+        self.control = (self.control & IRQ_ENABLE) | prescaler as u16 | COUNT_UP_OFF;
+        self.restart(reload);
+    }
+
+    /// Starts the timer in cascade mode, counting up once each time the previous timer overflows.
+    ///
+    /// Timer 0 can't be used in cascade mode.
+    pub fn start_cascade(&mut self, reload: u16) {
+        debug_assert!(self.index != 0, "timer 0 can't be used in cascade mode");
+        self.control = (self.control & IRQ_ENABLE) | COUNT_UP_ON;
+        self.restart(reload);
+    }
+
+    /// Starts the timer so that it overflows `hz` times per second.
+    ///
+    /// Picks the most accurate prescaler that can reach that frequency.
+    /// Frequencies from 1 Hz up to [`TIMER_FREQ`] can be used.
+    pub fn start_freq(&mut self, hz: u32) {
+        debug_assert!(hz >= 1 && hz <= TIMER_FREQ, "timer frequency must be from 1 to {TIMER_FREQ} Hz (was: {hz})");
+        let hz = hz.max(1);
+        for prescaler in [Prescaler::Div1, Prescaler::Div64, Prescaler::Div256, Prescaler::Div1024] {
+            let ticks = (prescaler.frequency() + hz / 2) / hz;
+            if ticks <= 0x10000 {
+                self.start(prescaler, (0x10000 - ticks.max(1)) as u16);
+                return;
+            }
+        }
+    }
+
+    fn restart(&mut self, reload: u16) {
+        self.reload = reload;
+        self.paused = false;
+        unsafe {
+            // the counter is loaded from the reload value when the start bit goes from 0 to 1
+            self.write_control(false);
+            ptr::write_volatile(self.counter_addr(), reload);
+        }
+        self.write_control(true);
+    }
+
+    /// Stops the timer. It can't be resumed, only started again.
+    pub fn stop(&mut self) {
+        self.paused = false;
+        self.write_control(false);
+    }
+
+    /// Stops the timer without losing the current count, so it can continue with [`Timer::resume`].
+    pub fn pause(&mut self) {
+        if self.is_running() {
+            self.write_control(false);
+            self.paused = true;
+        }
+    }
+
+    /// Continues a timer stopped with [`Timer::pause`], from the count it was at.
+    pub fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        self.paused = false;
+        let count = self.ticks();
+        unsafe {
+            // starting the timer loads the counter from the reload register, so briefly use
+            // that to restore the count. The real reload value is only needed at the next overflow.
+            ptr::write_volatile(self.counter_addr(), count);
+            self.write_control(true);
+            ptr::write_volatile(self.counter_addr(), self.reload);
+        }
+    }
+
+    /// Checks if the timer is currently counting.
+    #[must_use]
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        unsafe { ptr::read_volatile(self.control_addr()) & TIMER_START != 0 }
+    }
+
+    /// Gets the current value of the counter.
+    #[must_use]
+    #[inline]
+    pub fn ticks(&self) -> u16 {
+        unsafe { ptr::read_volatile(self.counter_addr()) }
+    }
+
+    /// The interrupt requested when this timer overflows.
+    #[must_use]
+    #[inline]
+    pub fn irq_type(&self) -> IRQType {
+        match self.index {
+            0 => IRQType::Timer0,
+            1 => IRQType::Timer1,
+            2 => IRQType::Timer2,
+            _ => IRQType::Timer3,
+        }
+    }
+
+    /// The interrupt flag for this timer.
+    #[must_use]
+    #[inline]
+    pub fn irq_flag(&self) -> IRQFlags {
+        IRQFlags::from_bits_retain(IRQFlags::TIMER0.bits() << self.index)
+    }
+
+    /// Sets a function that is called (from the interrupt handler) every time the timer overflows,
+    /// and enables the timer's interrupt.
+    ///
+    /// This replaces any handler set with [`irq_set_handler`] for this timer's interrupt.
+    pub fn set_overflow_handler<F: FnMut() + 'static>(&mut self, handler: F) {
+        irq_set_handler(self.irq_type(), handler);
+        self.control |= IRQ_ENABLE;
+        self.write_control(self.is_running());
+        irq_enable(self.irq_flag());
+    }
+
+    /// Checks if the timer requests its interrupt when it overflows.
+    #[inline]
+    pub(crate) fn irq_enabled(&self) -> bool {
+        self.control & IRQ_ENABLE != 0
+    }
+
+    /// Sets whether the timer requests its interrupt when it overflows, without changing the handler or IE.
+    pub(crate) fn set_irq_enabled(&mut self, enabled: bool) {
+        self.control = if enabled { self.control | IRQ_ENABLE } else { self.control & !IRQ_ENABLE };
+        self.write_control(self.is_running());
+    }
+
+    /// Removes the overflow handler, and disables the timer's interrupt.
+    pub fn remove_overflow_handler(&mut self) {
+        irq_disable(self.irq_flag());
+        self.control &= !IRQ_ENABLE;
+        self.write_control(self.is_running());
+        irq_remove_handler(self.irq_type());
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop();
+        if self.control & IRQ_ENABLE != 0 {
+            self.remove_overflow_handler();
+        }
+        critical_section!({
+            TIMERS_TAKEN.write(TIMERS_TAKEN.read() & !(1 << self.index));
+        });
+    }
+}
 
 /// Starts a profiler timer, used to measure code execution time.
 ///